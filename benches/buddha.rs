@@ -1,9 +1,8 @@
 extern crate buddhabrot;
 
-use std::sync::{Arc, Mutex};
-
-use buddhabrot::{color::Rgb, images::Image, sample::sample};
+use buddhabrot::{color::ColorChannel, complex::{Complex, Real}, formula::{Bailout, Formula, Iterate}, histogram::Histogram, progress::{CancelToken, Hooks, SnapshotToken}, sample::{sample, sample_with, Accumulation, Axis, Band, BandColor, OrbitKind, Precision, Projection, Sampler, Threading, Viewport}, state::RenderParams};
 use criterion::{criterion_group, criterion_main, Criterion};
+use indicatif::ProgressBar;
 
 
 const IM_WIDTH: usize = 256;
@@ -11,13 +10,69 @@ const IM_HEIGHT: usize = 256;
 const IM_SIZE: usize = IM_WIDTH * IM_HEIGHT;
 const PROGRESS_UPDATE: usize = IM_WIDTH;
 
-fn bench() {
-    let im = Image::<Rgb>::new(IM_SIZE, IM_WIDTH);
-    sample(Arc::new(Mutex::new(im)), 10000, 20, PROGRESS_UPDATE);
-} 
+fn params(cardioid_check: bool, periodicity_check: bool) -> RenderParams {
+    RenderParams {
+        n_iterations: 10000,
+        formula: Formula::Mandelbrot,
+        escape_radius: 2.0,
+        bailout: Bailout::Circle,
+        julia: None,
+        z0: Complex::new(0.0, 0.0),
+        samples: 20,
+        width: IM_WIDTH,
+        height: IM_HEIGHT,
+        bands: vec![Band::new(0, 10000, BandColor::Channel(ColorChannel::Red))],
+        orbit_kind: OrbitKind::Escaping,
+        viewport: Viewport::default(),
+        projection: Projection::default(),
+        sampler: Sampler::Uniform,
+        seed: 0,
+        cardioid_check,
+        periodicity_check,
+        precision: Precision::F32,
+    }
+}
+
+fn bench(params: &RenderParams, accumulation: Accumulation) {
+    let mut hist = Histogram::new(params.bands.len(), IM_SIZE, IM_WIDTH);
+    let threading = Threading { accumulation, ..Threading::default() };
+    let hooks = Hooks { progress: &ProgressBar::hidden(), progress_update: PROGRESS_UPDATE, cancel: &CancelToken::new(), snapshots: &SnapshotToken::new(), deadline: None };
+    sample(&mut hist, params, 0, params.total_samples(), threading, &hooks);
+}
+
+/// `z = z^3 + c`, implemented outside of the crate.
+#[derive(Clone, Copy)]
+struct Cubic;
+
+impl<R: Real> Iterate<R> for Cubic {
+    #[inline]
+    fn iterate(&self, z: Complex<R>, c: Complex<R>) -> Complex<R> {
+        z * z * z + c
+    }
+}
+
+fn bench_custom(params: &RenderParams) {
+    let mut hist = Histogram::new(params.bands.len(), IM_SIZE, IM_WIDTH);
+    let hooks = Hooks { progress: &ProgressBar::hidden(), progress_update: PROGRESS_UPDATE, cancel: &CancelToken::new(), snapshots: &SnapshotToken::new(), deadline: None };
+    sample_with::<f32, _>(&mut hist, params, Cubic, 0, params.total_samples(), Threading::default(), &hooks);
+}
 
 fn criterion_bench(c: &mut Criterion) {
-    c.bench_function("buddha sample 1", |b| b.iter(bench));
+    let unchecked = params(false, false);
+    let cardioid = params(true, false);
+    let periodicity = params(true, true);
+    let double = RenderParams { precision: Precision::F64, ..params(true, false) };
+    let projected = RenderParams { projection: Projection::axes(Axis::ZRe, Axis::CRe), ..params(false, false) };
+    let multibrot = RenderParams { formula: Formula::Multibrot { exponent: 2.5 }, ..params(false, false) };
+
+    c.bench_function("buddha sample 1", |b| b.iter(|| bench(&unchecked, Accumulation::Local)));
+    c.bench_function("buddha sample cardioid check", |b| b.iter(|| bench(&cardioid, Accumulation::Local)));
+    c.bench_function("buddha sample periodicity check", |b| b.iter(|| bench(&periodicity, Accumulation::Local)));
+    c.bench_function("buddha sample f64", |b| b.iter(|| bench(&double, Accumulation::Local)));
+    c.bench_function("buddha sample projected", |b| b.iter(|| bench(&projected, Accumulation::Local)));
+    c.bench_function("buddha sample multibrot", |b| b.iter(|| bench(&multibrot, Accumulation::Local)));
+    c.bench_function("buddha sample custom formula", |b| b.iter(|| bench_custom(&unchecked)));
+    c.bench_function("buddha sample atomic", |b| b.iter(|| bench(&cardioid, Accumulation::Atomic)));
 }
 
 criterion_group!(
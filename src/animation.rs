@@ -0,0 +1,135 @@
+use crate::sample::Viewport;
+
+
+/// The parameters of an animation at one of its frames. Every parameter is interpolated between
+/// the keyframes around a frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Keyframe {
+    /// The index of the frame this keyframe sets the parameters of.
+    pub frame: u32,
+    pub viewport: Viewport,
+    /// The angles in radians that the 4D space is rotated by, as in [`Projection::rotated`].
+    ///
+    /// [`Projection::rotated`]: crate::sample::Projection::rotated
+    pub rotation_4d: [f64; 6],
+    /// The number of iterations each complex number undergoes.
+    pub n_iterations: u32,
+    /// The exponent of the Multibrot formula. Ignored by other formulas.
+    pub exponent: f64,
+}
+
+impl Keyframe {
+    /// Interpolates between this keyframe and `next` by `t`, where 0 gives this keyframe and 1
+    /// gives `next`. The zoom changes exponentially, so that zooming in has a constant speed.
+    /// The center moves at a constant speed on screen rather than on the complex plane, so that
+    /// the point being zoomed into comes into view at the start instead of the end.
+    pub fn lerp(&self, next: &Keyframe, t: f64) -> Keyframe {
+        let lerp = |a: f64, b: f64| a + (b - a) * t;
+        let (a, b) = (self.viewport, next.viewport);
+        let zoom = (lerp(a.zoom.ln(), b.zoom.ln())).exp();
+        // The width of the view shrinks as 1 / zoom, so moving the center by the same fraction
+        // of the change in width keeps it moving steadily across the screen
+        let s = if a.zoom == b.zoom { t } else { (1.0 / a.zoom - 1.0 / zoom) / (1.0 / a.zoom - 1.0 / b.zoom) };
+        let center = a.center + (b.center - a.center) * s;
+
+        let mut rotation_4d = self.rotation_4d;
+        for (angle, next) in rotation_4d.iter_mut().zip(next.rotation_4d) {
+            *angle = lerp(*angle, next);
+        }
+
+        Keyframe {
+            frame: lerp(self.frame as f64, next.frame as f64).round() as u32,
+            viewport: Viewport::new(center, zoom, lerp(a.angle, b.angle)),
+            rotation_4d,
+            n_iterations: lerp(self.n_iterations as f64, next.n_iterations as f64).round() as u32,
+            exponent: lerp(self.exponent, next.exponent),
+        }
+    }
+}
+
+
+/// A sequence of frames whose parameters are interpolated between keyframes.
+#[derive(Clone, Debug)]
+pub struct Animation {
+    /// The keyframes, sorted by frame.
+    keyframes: Vec<Keyframe>,
+}
+
+impl Animation {
+    /// Creates an animation through `keyframes`, which must not be empty. If several keyframes
+    /// set the same frame, the last one wins.
+    pub fn new(mut keyframes: Vec<Keyframe>) -> Animation {
+        assert!(!keyframes.is_empty(), "an animation needs at least one keyframe");
+        keyframes.reverse();
+        keyframes.sort_by_key(|key| key.frame);
+        keyframes.dedup_by_key(|key| key.frame);
+        Self { keyframes }
+    }
+
+    /// The number of frames in the animation, which ends at its last keyframe.
+    #[inline]
+    pub fn frame_count(&self) -> u32 {
+        self.keyframes.last().unwrap().frame + 1
+    }
+
+    /// The parameters of the animation at `frame`. Frames before the first keyframe or after the
+    /// last one hold still.
+    pub fn at(&self, frame: u32) -> Keyframe {
+        let next = self.keyframes.partition_point(|key| key.frame <= frame);
+        let key = match (next.checked_sub(1).map(|i| &self.keyframes[i]), self.keyframes.get(next)) {
+            (Some(prev), Some(next)) if prev.frame != frame => {
+                prev.lerp(next, (frame - prev.frame) as f64 / (next.frame - prev.frame) as f64)
+            },
+            (Some(prev), _) => *prev,
+            (None, next) => *next.unwrap(),
+        };
+        Keyframe { frame, ..key }
+    }
+
+    /// The parameters of every frame of the animation, in order.
+    pub fn frames(&self) -> impl Iterator<Item = Keyframe> + '_ {
+        (0..self.frame_count()).map(|frame| self.at(frame))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::complex::Complex;
+
+    fn keyframe(frame: u32, center: Complex<f64>, zoom: f64) -> Keyframe {
+        Keyframe { frame, viewport: Viewport::new(center, zoom, 0.0), rotation_4d: [0.0; 6], n_iterations: 100, exponent: 2.0 }
+    }
+
+    #[test]
+    fn zoom_keeps_target_in_view() {
+        let (start, target) = (Complex::new(0.0, 0.0), Complex::new(-0.75, 0.1));
+        let animation = Animation::new(vec![keyframe(10, target, 1e6), keyframe(0, start, 1.0)]);
+        assert_eq!(animation.frame_count(), 11);
+        assert_eq!(animation.at(0), keyframe(0, start, 1.0));
+        assert_eq!(animation.at(10), keyframe(10, target, 1e6));
+        assert_eq!(animation.at(20).viewport, animation.at(10).viewport);
+
+        // The zoom changes by the same factor on every frame
+        assert!((animation.at(5).viewport.zoom - 1e3).abs() < 1e-6);
+
+        // The target never gets further from the center of the screen than where it starts
+        let distance = |c: Complex<f64>| c.norm_sqr().sqrt();
+        let mut last = f64::INFINITY;
+        for key in animation.frames() {
+            let offset = distance(key.viewport.transform_inverse(target));
+            assert!(offset <= distance(target - start) + 1e-9, "frame {} has the target {} away", key.frame, offset);
+            assert!(offset <= last);
+            last = offset;
+        }
+    }
+
+    #[test]
+    fn last_keyframe_of_a_frame_wins() {
+        let (a, b) = (Complex::new(0.5, 0.0), Complex::new(-0.5, 0.0));
+        let animation = Animation::new(vec![keyframe(0, a, 1.0), keyframe(4, a, 1.0), keyframe(4, b, 1.0)]);
+        assert_eq!(animation.at(4).viewport.center, b);
+        assert_eq!(animation.at(2).viewport.center, Complex::new(0.0, 0.0));
+    }
+}
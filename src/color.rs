@@ -1,6 +1,6 @@
 pub type Float = f32;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ColorChannel {
     Red,
     Green,
@@ -50,6 +50,45 @@ impl Color for Float {
 }
 
 
+/// A hit counter. Unlike a float, it keeps counting exactly no matter how many hits it gets.
+///
+/// Images of hit counters are only meant for counting. `map` and `cdiv_assign` round their
+/// results down to whole counts, so they have to be converted to a float image, such as with
+/// [`Histogram::to_image`](crate::histogram::Histogram::to_image), before being normalized or
+/// tone mapped.
+impl Color for u64 {
+    #[inline]
+    fn empty() -> Self {
+        0
+    }
+    #[inline]
+    fn add(&mut self, rhs: Self) {
+        *self += rhs
+    }
+    #[inline]
+    fn max(self, rhs: Self) -> Self {
+        Ord::max(self, rhs)
+    }
+    #[inline]
+    fn map(self, f: impl Fn(Float) -> Float) -> Self {
+        f(self as Float) as u64
+    }
+    #[inline]
+    fn one(_channel: ColorChannel) -> Self {
+        1
+    }
+    #[inline]
+    fn cdiv_assign(&mut self, rhs: Self) {
+        *self /= rhs
+    }
+    #[inline]
+    fn to_tuple_rgb(self) -> (Float, Float, Float) {
+        let v = self as Float;
+        (v, v, v)
+    }
+}
+
+
 #[derive(Clone, Copy, Debug)]
 pub struct Rg {
     pub r: Float,
@@ -128,7 +167,7 @@ impl Color for Rg {
 }
 
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Rgb {
     pub r: Float,
     pub g: Float,
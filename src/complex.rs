@@ -1,6 +1,8 @@
-use std::ops::{Add, Div, Mul, Sub};
+use std::{fmt::Debug, ops::{Add, Div, Mul, Neg, Sub}};
 
-#[derive(Clone, Copy, Debug)]
+use rand::Rng;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Complex<T> {
     pub re: T,
     pub im: T,
@@ -113,6 +115,66 @@ impl<T: Hypot> Complex<T> {
     }
 }
 
+impl<T: Clone + Copy + Mul<T, Output = T> + Add<T, Output = T>> Complex<T> {
+    /// Computes the squared magnitude of a complex number. This is much cheaper than
+    /// [`Complex::abs`], so prefer comparing against a squared value where possible.
+    #[inline]
+    pub fn norm_sqr(self) -> T {
+        self.re * self.re + self.im * self.im
+    }
+}
+
+
+impl<T: Real> Complex<T> {
+    /// Creates a complex number from its polar form, with magnitude `r` and argument `theta`.
+    #[inline]
+    pub fn from_polar(r: T, theta: T) -> Complex<T> {
+        let (sin, cos) = theta.sin_cos();
+        Self::new(r * cos, r * sin)
+    }
+
+    /// Computes the argument (angle) of a complex number, in the range [-pi, pi].
+    #[inline]
+    pub fn arg(self) -> T {
+        self.im.atan2(self.re)
+    }
+
+    /// Converts a complex number to its polar form, returning its magnitude and argument.
+    #[inline]
+    pub fn to_polar(self) -> (T, T) {
+        (self.abs(), self.arg())
+    }
+
+    /// Computes the complex conjugate of a complex number.
+    #[inline]
+    pub fn conj(self) -> Complex<T> {
+        Self::new(self.re, -self.im)
+    }
+
+    /// Raises a complex number to an integer power by repeated squaring.
+    #[inline]
+    pub fn powi(self, mut n: u32) -> Complex<T> {
+        let mut base = self;
+        let mut result = Self::new(T::from_f64(1.0), T::from_f64(0.0));
+        while n > 0 {
+            if n & 1 == 1 {
+                result = result * base;
+            }
+            base = base * base;
+            n >>= 1;
+        }
+        result
+    }
+
+    /// Raises a complex number to a real power through its polar form, taking the principal
+    /// branch for non-integer powers.
+    #[inline]
+    pub fn powf(self, n: T) -> Complex<T> {
+        let (r, theta) = self.to_polar();
+        Self::from_polar(r.powf(n), theta * n)
+    }
+}
+
 
 pub trait Hypot {
     fn hypotenuse(self, rhs: Self) -> Self;
@@ -131,6 +193,73 @@ impl Hypot for f64 {
 }
 
 
+/// A floating point type that orbits can be computed with.
+pub trait Real:
+    Clone + Copy + Debug + PartialOrd + Send + Sync + 'static + Hypot
+    + Add<Self, Output = Self> + Sub<Self, Output = Self> + Mul<Self, Output = Self> + Div<Self, Output = Self> + Neg<Output = Self>
+{
+    /// The difference between 1 and the next larger representable number.
+    const EPSILON: Self;
+
+    /// Converts an `f64` to this type, rounding to the nearest representable value.
+    fn from_f64(v: f64) -> Self;
+    /// Converts this value to an `f64`.
+    fn to_f64(self) -> f64;
+    fn abs(self) -> Self;
+    fn exp(self) -> Self;
+    fn sin_cos(self) -> (Self, Self);
+    fn atan2(self, other: Self) -> Self;
+    fn powf(self, n: Self) -> Self;
+    /// Generates a uniformly distributed random number in [0, 1).
+    fn random<R: Rng>(rng: &mut R) -> Self;
+}
+
+macro_rules! impl_real {
+    ($($t:ident),*) => {
+        $(
+            impl Real for $t {
+                const EPSILON: Self = $t::EPSILON;
+
+                #[inline]
+                fn from_f64(v: f64) -> Self {
+                    v as $t
+                }
+                #[inline]
+                fn to_f64(self) -> f64 {
+                    self as f64
+                }
+                #[inline]
+                fn abs(self) -> Self {
+                    $t::abs(self)
+                }
+                #[inline]
+                fn exp(self) -> Self {
+                    $t::exp(self)
+                }
+                #[inline]
+                fn sin_cos(self) -> (Self, Self) {
+                    $t::sin_cos(self)
+                }
+                #[inline]
+                fn atan2(self, other: Self) -> Self {
+                    $t::atan2(self, other)
+                }
+                #[inline]
+                fn powf(self, n: Self) -> Self {
+                    $t::powf(self, n)
+                }
+                #[inline]
+                fn random<R: Rng>(rng: &mut R) -> Self {
+                    rng.gen_range(0.0..1.0)
+                }
+            }
+        )*
+    }
+}
+
+impl_real!(f32, f64);
+
+
 macro_rules! impl_op_real {
     ($($op:tt, $fn:ident, $trait:ident);*) => {
         $(
@@ -0,0 +1,176 @@
+use crate::{complex::{Complex, Real}, sample::in_main_bulbs};
+
+
+/// The formula that orbits are iterated with, which picks the fractal a render is based on.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Formula {
+    /// `z = z^2 + c`, giving the regular Buddhabrot.
+    #[default]
+    Mandelbrot,
+    /// `z = z^d + c` for any real exponent `d` greater than 1. Non-integer exponents take the
+    /// principal branch of the power.
+    Multibrot {
+        exponent: f64,
+    },
+    /// `z = (|Re z| + i|Im z|)^2 + c`. The fractal appears upside down compared to the usual
+    /// images of the Burning Ship, since the imaginary axis points up.
+    BurningShip,
+    /// `z = conj(z)^2 + c`, also known as the Mandelbar.
+    Tricorn,
+    /// `z = |Re(z^2)| + i Im(z^2) + c`.
+    Celtic,
+}
+
+
+/// A single step of an iteration formula, computed with the float type `R`. Implement this to
+/// render the orbits of a custom fractal with [`sample_with`](crate::sample::sample_with).
+pub trait Iterate<R: Real>: Clone + Copy + Send + Sync {
+    /// Computes the point that follows `z` in the orbit of `c`.
+    fn iterate(&self, z: Complex<R>, c: Complex<R>) -> Complex<R>;
+
+    /// Whether `c` is known to lie in a region whose orbits never escape, so that it can be
+    /// skipped without iterating it. The default knows of no such region.
+    #[inline]
+    fn in_interior(&self, _c: Complex<R>) -> bool {
+        false
+    }
+}
+
+
+/// Implements [`Formula::Mandelbrot`].
+#[derive(Clone, Copy, Debug)]
+pub struct Mandelbrot;
+
+impl<R: Real> Iterate<R> for Mandelbrot {
+    #[inline]
+    fn iterate(&self, z: Complex<R>, c: Complex<R>) -> Complex<R> {
+        z * z + c
+    }
+
+    #[inline]
+    fn in_interior(&self, c: Complex<R>) -> bool {
+        in_main_bulbs(c)
+    }
+}
+
+/// Implements [`Formula::Multibrot`]. Integer exponents are computed by repeated squaring,
+/// which is much faster and more accurate than going through the polar form.
+#[derive(Clone, Copy, Debug)]
+pub struct Multibrot<R> {
+    exponent: R,
+    integer: Option<u32>,
+}
+
+impl<R: Real> Multibrot<R> {
+    #[inline]
+    pub fn new(exponent: f64) -> Multibrot<R> {
+        let integer = (exponent.fract() == 0.0 && exponent <= u32::MAX as f64).then_some(exponent as u32);
+        Self { exponent: R::from_f64(exponent), integer }
+    }
+}
+
+impl<R: Real> Iterate<R> for Multibrot<R> {
+    #[inline]
+    fn iterate(&self, z: Complex<R>, c: Complex<R>) -> Complex<R> {
+        match self.integer {
+            Some(n) => z.powi(n) + c,
+            None => z.powf(self.exponent) + c,
+        }
+    }
+}
+
+/// Implements [`Formula::BurningShip`].
+#[derive(Clone, Copy, Debug)]
+pub struct BurningShip;
+
+impl<R: Real> Iterate<R> for BurningShip {
+    #[inline]
+    fn iterate(&self, z: Complex<R>, c: Complex<R>) -> Complex<R> {
+        let z = z.map(R::abs);
+        z * z + c
+    }
+}
+
+/// Implements [`Formula::Tricorn`].
+#[derive(Clone, Copy, Debug)]
+pub struct Tricorn;
+
+impl<R: Real> Iterate<R> for Tricorn {
+    #[inline]
+    fn iterate(&self, z: Complex<R>, c: Complex<R>) -> Complex<R> {
+        let z = z.conj();
+        z * z + c
+    }
+}
+
+/// Implements [`Formula::Celtic`].
+#[derive(Clone, Copy, Debug)]
+pub struct Celtic;
+
+impl<R: Real> Iterate<R> for Celtic {
+    #[inline]
+    fn iterate(&self, z: Complex<R>, c: Complex<R>) -> Complex<R> {
+        let z2 = z * z;
+        Complex::new(z2.re.abs(), z2.im) + c
+    }
+}
+
+
+/// The shape of the region outside of which an orbit counts as escaped, given an escape radius
+/// `r`. Shapes other than the circle change which orbits escape and when, giving renders their
+/// own distinct styles.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Bailout {
+    /// `|z| > r`, the usual test.
+    #[default]
+    Circle,
+    /// `max(|Re z|, |Im z|) > r`.
+    Square,
+    /// `|Re z| + |Im z| > r`.
+    Manhattan,
+    /// `|Re z| > r`.
+    RealPart,
+    /// `|Im z| > r`.
+    ImaginaryPart,
+}
+
+impl Bailout {
+    /// The smallest magnitude that a point can have and still escape with an escape radius of
+    /// `radius`. Orbits that never leave the disc of this radius never escape.
+    pub fn min_escape_magnitude(self, radius: f64) -> f64 {
+        match self {
+            Bailout::Manhattan => radius / std::f64::consts::SQRT_2,
+            Bailout::Circle | Bailout::Square | Bailout::RealPart | Bailout::ImaginaryPart => radius,
+        }
+    }
+}
+
+
+/// A [`Bailout`] test with its escape radius converted to the float type `R`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct EscapeTest<R> {
+    bailout: Bailout,
+    radius: R,
+    /// The squared escape radius, which the circle test compares the squared magnitude of `z`
+    /// against to avoid a square root.
+    radius_sqr: R,
+}
+
+impl<R: Real> EscapeTest<R> {
+    #[inline]
+    pub(crate) fn new(bailout: Bailout, radius: f64) -> EscapeTest<R> {
+        Self { bailout, radius: R::from_f64(radius), radius_sqr: R::from_f64(radius * radius) }
+    }
+
+    /// Whether `z` lies outside the bailout region, so that its orbit has escaped.
+    #[inline]
+    pub(crate) fn escaped(&self, z: Complex<R>) -> bool {
+        match self.bailout {
+            Bailout::Circle => z.norm_sqr() > self.radius_sqr,
+            Bailout::Square => z.re.abs() > self.radius || z.im.abs() > self.radius,
+            Bailout::Manhattan => z.re.abs() + z.im.abs() > self.radius,
+            Bailout::RealPart => z.re.abs() > self.radius,
+            Bailout::ImaginaryPart => z.im.abs() > self.radius,
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{color::{Color, Float, Rgb}, images::Image};
+
+
+/// The raw hit counts of a render. Every color that orbits get plotted with has its own layer of
+/// integer counters, which are only turned into a float image once the render is written out.
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    pub layers: Vec<Image<u64>>,
+    /// The hits of every layer together, counting only the samples of even numbered chunks.
+    /// Comparing these against the hits of the odd numbered chunks estimates the noise.
+    pub even: Image<u64>,
+}
+
+impl Histogram {
+    /// Creates a new, empty histogram of `layers` layers, each holding `size` pixels.
+    #[inline]
+    pub fn new(layers: usize, size: usize, width: usize) -> Histogram {
+        Self { layers: vec![Image::new(size, width); layers], even: Image::new(size, width) }
+    }
+
+    /// Adds the hit counts of another histogram of the same shape to this one.
+    pub fn merge(&mut self, other: &Histogram) {
+        for (layer, other) in self.layers.iter_mut().chain([&mut self.even]).zip(other.layers.iter().chain([&other.even])) {
+            for (x, y, count) in other.enumerate_pixels() {
+                layer.add((x, y), *count);
+            }
+        }
+    }
+
+    /// Resets every hit count to 0.
+    pub fn clear(&mut self) {
+        for layer in self.layers.iter_mut().chain([&mut self.even]) {
+            layer.pixels_mut().for_each(|count| *count = 0);
+        }
+    }
+
+    /// Converts the hit counts to an image, weighting every layer by its color in `colors`.
+    pub fn to_image(&self, colors: &[Rgb]) -> Image<Rgb> {
+        let mut im = Image::<Rgb>::new(self.even.size, self.even.width);
+
+        for (layer, color) in self.layers.iter().zip(colors.iter()) {
+            for (x, y, count) in layer.enumerate_pixels() {
+                let count = *count as Float;
+                im.add((x, y), color.map(|c| c * count));
+            }
+        }
+        im
+    }
+
+    /// Estimates the noise of a render whose hits come from `even_samples` samples of even
+    /// numbered chunks and `odd_samples` samples of odd numbered chunks, as the root mean square
+    /// error of its hit counts relative to their root mean square. The error is estimated from the
+    /// difference between the hits of the even and odd numbered chunks, which are independent
+    /// renders of the same image. Returns `None` if there is nothing to compare.
+    pub fn noise(&self, even_samples: usize, odd_samples: usize) -> Option<f64> {
+        if even_samples == 0 || odd_samples == 0 {
+            return None;
+        }
+
+        let mut totals = vec![0u64; self.even.size];
+        for layer in self.layers.iter() {
+            for (total, count) in totals.iter_mut().zip(layer.pixels()) {
+                *total += count;
+            }
+        }
+
+        let (even_samples, odd_samples) = (even_samples as f64, odd_samples as f64);
+        let (mut error, mut signal) = (0.0, 0.0);
+        for (&total, &even) in totals.iter().zip(self.even.pixels()) {
+            // Compare the hit rates per sample, since the halves can differ in size
+            let diff = even as f64 / even_samples - (total - even) as f64 / odd_samples;
+            error += diff * diff;
+            signal += total as f64 * total as f64;
+        }
+        if signal == 0.0 {
+            return None;
+        }
+
+        // The difference between the hit rates of the halves has a variance of
+        // samples / (even * odd) times that of a single sample, so scaling it by even * odd gives
+        // the variance of the total hits
+        Some((error * even_samples * odd_samples / signal).sqrt())
+    }
+}
+
+
+/// Somewhere the hits of orbits can be counted.
+pub(crate) trait Accumulator {
+    /// Adds a hit to pixel `px` of every layer in `layers`, from a sample in an even numbered
+    /// chunk if `even` is set.
+    fn hit(&mut self, px: (usize, usize), layers: &[usize], even: bool);
+}
+
+impl Accumulator for Histogram {
+    #[inline]
+    fn hit(&mut self, px: (usize, usize), layers: &[usize], even: bool) {
+        for &layer in layers {
+            self.layers[layer].add(px, 1);
+        }
+        if even {
+            self.even.add(px, layers.len() as u64);
+        }
+    }
+}
+
+
+/// An atomic view of a histogram's counters, which lets every thread add hits to the histogram
+/// at once without keeping a copy of its own.
+pub(crate) struct AtomicHistogram<'a> {
+    layers: Vec<&'a [AtomicU64]>,
+    even: &'a [AtomicU64],
+    width: usize,
+}
+
+impl<'a> AtomicHistogram<'a> {
+    /// Borrows the counters of `hist` for as long as the view lives.
+    pub(crate) fn new(hist: &'a mut Histogram) -> AtomicHistogram<'a> {
+        let width = hist.even.width;
+        let mut layers: Vec<_> = hist.layers.iter_mut().chain([&mut hist.even]).map(|layer| atomic(layer.as_mut_slice())).collect();
+        let even = layers.pop().unwrap();
+        Self { layers, even, width }
+    }
+
+    /// Copies the hit counts counted so far, while other threads may still be adding to them.
+    pub(crate) fn snapshot(&self) -> Histogram {
+        let mut hist = Histogram::new(self.layers.len(), self.even.len(), self.width);
+        for (layer, counts) in hist.layers.iter_mut().chain([&mut hist.even]).zip(self.layers.iter().chain([&self.even])) {
+            for (px, count) in layer.pixels_mut().zip(counts.iter()) {
+                *px = count.load(Ordering::Relaxed);
+            }
+        }
+        hist
+    }
+}
+
+/// Views a slice of counters as atomic counters, like the unstable `AtomicU64::from_mut_slice`.
+fn atomic(counts: &mut [u64]) -> &[AtomicU64] {
+    // Some 32-bit targets only align `u64` to 4 bytes, while `AtomicU64` always needs 8
+    assert!(counts.as_ptr().cast::<AtomicU64>().is_aligned(), "histogram counters are not aligned for atomic access");
+    // SAFETY: `AtomicU64` has the same size and bit validity as `u64`, the alignment was checked
+    // above, and the exclusive borrow keeps the counters from being accessed other than through
+    // the returned slice while it lives
+    unsafe { &*(counts as *mut [u64] as *const [AtomicU64]) }
+}
+
+impl Accumulator for &AtomicHistogram<'_> {
+    #[inline]
+    fn hit(&mut self, px: (usize, usize), layers: &[usize], even: bool) {
+        let index = px.1 * self.width + px.0;
+        // The counts are only read once every thread is done, or for snapshots that don't need
+        // to be exact, so no ordering is needed
+        for &layer in layers {
+            self.layers[layer][index].fetch_add(1, Ordering::Relaxed);
+        }
+        if even {
+            self.even[index].fetch_add(layers.len() as u64, Ordering::Relaxed);
+        }
+    }
+}
@@ -22,6 +22,12 @@ impl<T: Color + Clone + Copy> Image<T> {
         Self { data: vec![col; size], size, width }
     }
 
+    /// The height of the image in pixels.
+    #[inline]
+    pub fn height(&self) -> usize {
+        self.size.checked_div(self.width).unwrap_or(0)
+    }
+
     /// Gets the value of a pixel at a given `(x, y)` pixel position.
     #[inline]
     pub fn get(&self, px: (usize, usize)) -> T {
@@ -48,18 +54,24 @@ impl<T: Color + Clone + Copy> Image<T> {
 
     /// Get an iterator over every pixel in the image.
     #[inline]
-    pub fn pixels(&self) -> Pixels<T> {
+    pub fn pixels(&self) -> Pixels<'_, T> {
         Pixels { iter: self.data.iter() }
     }
 
     /// Get a mutable iterator over every pixel in the image.
     #[inline]
-    pub fn pixels_mut(&mut self) -> PixelsMut<T> {
+    pub fn pixels_mut(&mut self) -> PixelsMut<'_, T> {
         PixelsMut { iter: self.data.iter_mut() }
     }
 
+    /// Get every pixel in the image as a mutable slice, in row-major order.
+    #[inline]
+    pub(crate) fn as_mut_slice(&mut self) -> &mut [T] {
+        &mut self.data
+    }
+
     #[inline]
-    pub fn enumerate_pixels(&self) -> EnumeratePixels<T> {
+    pub fn enumerate_pixels(&self) -> EnumeratePixels<'_, T> {
         EnumeratePixels { iter: self.data.iter(), index: 0, size: self.size, width: self.width }
     }
 
@@ -69,7 +81,7 @@ impl<T: Color + Clone + Copy> Image<T> {
     }
 
     #[inline]
-    pub fn enumerate_pixels_mut(&mut self) -> EnumeratePixelsMut<T> {
+    pub fn enumerate_pixels_mut(&mut self) -> EnumeratePixelsMut<'_, T> {
         EnumeratePixelsMut { iter: self.data.iter_mut(), index: 0, size: self.size, width: self.width }
     }
 }
@@ -1,4 +1,10 @@
+pub mod animation;
 pub mod complex;
+pub mod formula;
 pub mod images;
 pub mod color;
+pub mod histogram;
+pub mod progress;
+pub mod render;
 pub mod sample;
+pub mod state;
@@ -1,8 +1,8 @@
-use std::{path::PathBuf, sync::{Arc, Mutex}};
-use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand, ValueEnum};
+use std::{num::{NonZeroU32, NonZeroUsize}, path::{Path, PathBuf}, sync::{mpsc::{self, RecvTimeoutError, Sender, TryRecvError}, Arc}, thread::{self, JoinHandle}, time::{Duration, Instant}};
+use clap::{error::ErrorKind, Args, CommandFactory, Parser, Subcommand, ValueEnum};
 use image::GenericImageView;
 
-use buddhabrot::{color::{Color, Float, Rgb}, images::Image, sample::sample};
+use buddhabrot::{animation::{Animation, Keyframe}, color::{Color, ColorChannel, Rgb}, complex::Complex, formula::{Bailout, Formula}, images::Image, progress::progress_bar, render::{RenderConfig, Renderer}, sample::{Accumulation, Axis, Band, BandColor, OrbitKind, Precision, Projection, Sampler, Threading, Viewport}, state::RenderState};
 
 
 fn normalize_im<T: Color + Clone + Copy + Send + Sync + 'static>(im: &mut Image<T>) {
@@ -17,34 +17,303 @@ fn normalize_im<T: Color + Clone + Copy + Send + Sync + 'static>(im: &mut Image<
 }
 
 fn reflect_im<T: Color + Clone + Copy>(im: &mut Image<T>) {
-    for i in 0..im.size / 2 {
-        let x = i % im.width;
-        let y = i / im.width;
-        let c1 = im.get((x, y));
-        let c2 = im.get((x, im.width - y - 1));
-        im.add((x, im.width - y - 1), c1);
-        im.add((x, y), c2);
-    }
-}
-
-fn rotate_im<T: Color + Clone + Copy>(im: &mut Image<T>) {
-    for i in 0..im.size {
-        let x = i % im.width;
-        let y = i / im.width;
-        if x > y {
-            im.swap((x, y), (y, x));
+    let height = im.height();
+    for y in 0..height.div_ceil(2) {
+        let y_mirror = height - y - 1;
+        for x in 0..im.width {
+            let c1 = im.get((x, y));
+            let c2 = im.get((x, y_mirror));
+            if y == y_mirror {
+                im.add((x, y), c1);
+            } else {
+                im.add((x, y_mirror), c1);
+                im.add((x, y), c2);
+            }
+        }
+    }
+}
+
+fn rotate_im<T: Color + Clone + Copy>(im: Image<T>) -> Image<T> {
+    // Transpose the image, swapping its width and height
+    let mut rotated = Image::<T>::new(im.size, im.height());
+    for (x, y, px) in im.into_enumerate_pixels() {
+        rotated.set((y, x), px);
+    }
+    rotated
+}
+
+/// Applies the exposure, gamma and black point adjustments of the process command to an image,
+/// clamping its pixels between 0-1 if `clamp` is set.
+fn tone_map_im(im: &mut Image<Rgb>, exposure: Option<f32>, gamma: Option<f32>, black_point: Option<f32>, clamp: bool) {
+    if let Some(exp) = exposure {
+        for px in im.pixels_mut() {
+            px.r *= exp;
+            px.g *= exp;
+            px.b *= exp;
+        }
+    }
+
+    if let Some(gam) = gamma {
+        for px in im.pixels_mut() {
+            px.r = px.r.powf(1.0 / gam);
+            px.g = px.g.powf(1.0 / gam);
+            px.b = px.b.powf(1.0 / gam);
+        }
+    }
+
+    if let Some(thres) = black_point {
+        for px in im.pixels_mut() {
+            px.r = if px.r < thres { 0.0 } else { px.r };
+            px.g = if px.g < thres { 0.0 } else { px.g };
+            px.b = if px.b < thres { 0.0 } else { px.b };
+        }
+    }
+
+    if clamp {
+        for px in im.pixels_mut() {
+            px.r = px.r.clamp(0.0, 1.0);
+            px.g = px.g.clamp(0.0, 1.0);
+            px.b = px.b.clamp(0.0, 1.0);
         }
     }
 }
 
-fn fuse(im1: Image<f32>, im2: Image<f32>, im3: Image<f32>) -> Image<Rgb> {
-    let mut im = Image::<Rgb>::new(im1.size, im1.width);
-    for (x, y, px) in im1.into_enumerate_pixels() {
-        let py = im2.get((x, y));
-        let pz = im3.get((x, y));
-        im.set((x, y), Rgb::new(px, py, pz));
+/// The file and tone mapping of render previews.
+struct PreviewStyle {
+    file: PathBuf,
+    exposure: f32,
+    gamma: f32,
+    reflect: bool,
+    rotate: bool,
+}
+
+impl PreviewStyle {
+    /// Normalizes and tone maps an unnormalized render image, then writes it as a PNG.
+    fn write(&self, mut im: Image<Rgb>) {
+        normalize_im(&mut im);
+        if self.reflect {
+            reflect_im(&mut im);
+            normalize_im(&mut im);
+        }
+        if self.rotate {
+            im = rotate_im(im);
+        }
+        tone_map_im(&mut im, Some(self.exposure), Some(self.gamma), None, true);
+        write_rgb(im, self.file.clone(), true);
+    }
+}
+
+/// Periodically writes a tone-mapped PNG preview of a render in progress. Previews are written
+/// on a separate thread, so sampling carries on while they are encoded. Timed previews are also
+/// taken from that thread as snapshots of the render, without waiting for a batch to finish.
+struct Previewer {
+    style: Arc<PreviewStyle>,
+    interval: Duration,
+    batches: Option<u32>,
+    batches_since: u32,
+    writer: Option<JoinHandle<()>>,
+    /// Stops the thread taking timed previews when dropped.
+    stop: Option<Sender<()>>,
+}
+
+impl Previewer {
+    /// Creates a previewer if a preview file was requested.
+    fn new(preview: &PreviewArgs, output: &OutputArgs) -> Option<Previewer> {
+        Some(Self {
+            style: Arc::new(PreviewStyle {
+                file: preview.preview.clone()?,
+                exposure: preview.preview_exposure,
+                gamma: preview.preview_gamma,
+                reflect: output.reflect,
+                rotate: output.rotate,
+            }),
+            interval: preview.preview_interval,
+            batches: preview.preview_batches.map(NonZeroU32::get),
+            batches_since: 0,
+            writer: None,
+            stop: None,
+        })
+    }
+
+    /// Starts taking timed previews of `renderer`, unless previews are written after a number
+    /// of batches instead.
+    fn start(&mut self, renderer: &Renderer) {
+        if self.batches.is_some() {
+            return;
+        }
+
+        let (stop, stopped) = mpsc::channel();
+        let (style, interval, snapshots) = (self.style.clone(), self.interval, renderer.snapshot_token());
+        let params = &renderer.state().params;
+        let colors = params.orbit_kind.layer_colors(&params.bands);
+        self.writer = Some(thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                // Snapshots are only taken while batches are rendering, so keep checking whether
+                // the render has finished while waiting for one
+                let hist = loop {
+                    if let Some(hist) = snapshots.take(Duration::from_millis(100)) {
+                        break hist;
+                    }
+                    if stopped.try_recv() != Err(TryRecvError::Empty) {
+                        return;
+                    }
+                };
+                style.write(hist.to_image(&colors));
+            }
+        }));
+        self.stop = Some(stop);
+    }
+
+    /// Counts a finished batch, writing a preview of the render if one is due.
+    fn update(&mut self, renderer: &Renderer) {
+        let Some(batches) = self.batches else {
+            return;
+        };
+        self.batches_since += 1;
+        if self.batches_since < batches {
+            return;
+        }
+
+        // Only one preview is written at a time, so a slow disk can't pile them up
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+
+        let (style, im) = (self.style.clone(), renderer.image());
+        self.writer = Some(thread::spawn(move || style.write(im)));
+        self.batches_since = 0;
+    }
+
+    /// Stops taking previews and waits for the last one to be written.
+    fn finish(self) {
+        drop(self.stop);
+        if let Some(writer) = self.writer {
+            let _ = writer.join();
+        }
+    }
+}
+
+/// Runs a render until it is done, writing the state to `checkpoint` every time `interval` has
+/// passed and writing previews, if any, as it goes.
+fn render(renderer: &mut Renderer, checkpoint: Option<&Path>, interval: Duration, progress_update: usize, mut preview: Option<Previewer>) -> std::io::Result<()> {
+    let bar = progress_bar(renderer.state().params.total_samples() as u64);
+    bar.inc(renderer.state().samples_done as u64);
+    renderer.set_progress(bar.clone(), progress_update);
+    let mut last_checkpoint = Instant::now();
+    if let Some(preview) = preview.as_mut() {
+        preview.start(renderer);
+    }
+
+    while !renderer.is_done() {
+        renderer.render_batch();
+
+        if let Some(path) = checkpoint {
+            if last_checkpoint.elapsed() >= interval || renderer.is_done() {
+                renderer.state().write(path)?;
+                last_checkpoint = Instant::now();
+            }
+        }
+
+        if let Some(preview) = preview.as_mut() {
+            preview.update(renderer);
+        }
+    }
+
+    if let Some(preview) = preview {
+        preview.finish();
+    }
+    bar.finish_and_clear();
+    Ok(())
+}
+
+/// Prints how many samples a render took and how noisy it is.
+fn print_summary(state: &RenderState, elapsed: Duration) {
+    let params = &state.params;
+    println!("Finished rendering buddhabrot in {}.", humantime::format_duration(Duration::new(elapsed.as_secs(), 0)));
+    println!("Took {}/{} samples ({:.2} per pixel).", state.samples_done, params.total_samples(), state.samples_done as f64 / (params.width * params.height) as f64);
+    match state.noise() {
+        Some(noise) => println!("Estimated noise: {:.3}%.", noise * 100.0),
+        None => println!("Estimated noise: unknown, too few samples."),
+    }
+}
+
+/// Sets up a render of the fractal described by the command line, taking `samples` samples per
+/// pixel of an image of `size` pixels, apart from its view and seed.
+fn render_config(n_iterations: u32, samples: u32, size: (usize, usize), color: &ColorArgs, fractal: &FractalArgs) -> RenderConfig {
+    let bands = color.bands(n_iterations);
+    RenderConfig::new(n_iterations, samples, size.0, size.1)
+        .formula(fractal.formula(fractal.exponent))
+        .bailout(fractal.escape_radius, fractal.bailout())
+        .julia(fractal.julia_c)
+        .z0(fractal.z0)
+        .orbit_kind(color.orbit_kind(&bands))
+        .bands(bands)
+        .sampler(fractal.sampler())
+        .cardioid_check(!fractal.no_cardioid_check)
+        .periodicity_check(fractal.periodicity_check)
+        .precision(fractal.precision())
+}
+
+/// Spreads a render over `threads` threads, or every CPU core if unspecified.
+fn threading(threads: Option<NonZeroUsize>, accumulation: AccumulationArg) -> Threading {
+    let threads = threads.map_or_else(num_cpus::get, NonZeroUsize::get);
+    Threading { threads, accumulation: accumulation.into() }
+}
+
+/// Checks that a render's output file can be written, returning its full path.
+fn output_file(output: &OutputArgs) -> clap::error::Result<PathBuf, clap::Error> {
+    checked_file(output.file.clone(), output)
+}
+
+/// Checks that the output file of every frame of an animation can be written, returning their
+/// full paths. Frames are numbered with at least four digits, such as `buddhabrot_0042.exr`.
+fn frame_files(output: &OutputArgs, frames: u32) -> clap::error::Result<Vec<PathBuf>, clap::Error> {
+    let digits = (frames.saturating_sub(1).checked_ilog10().unwrap_or(0) as usize + 1).max(4);
+    (0..frames).map(|frame| {
+        let mut file = output.file.clone().into_os_string();
+        file.push(format!("_{:0digits$}", frame));
+        checked_file(file.into(), output)
+    }).collect()
+}
+
+/// Adds the extension of the output format to `file` and checks that it can be written.
+fn checked_file(mut file: PathBuf, output: &OutputArgs) -> clap::error::Result<PathBuf, clap::Error> {
+    file.set_extension(if output.png { "png" } else { "exr" });
+    check_overwrite(&file, output)?;
+    Ok(file)
+}
+
+/// Checks that `file` doesn't exist yet, unless the output args allow overwriting it.
+fn check_overwrite(file: &Path, output: &OutputArgs) -> clap::error::Result<(), clap::Error> {
+    if file.exists() && !output.overwrite {
+        let err = Cli::command().error(ErrorKind::ValueValidation, format!("file {:?} already exists. to overwrite it, use the -o flag", file));
+        err.print()?;
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Applies the requested post-processing to a finished render and writes it to `file`.
+fn write_render(state: &RenderState, file: PathBuf, output: &OutputArgs) {
+    let mut im = state.image();
+
+    if output.normalize {
+        normalize_im(&mut im);
+    }
+
+    if output.reflect {
+        reflect_im(&mut im);
+    }
+
+    if output.rotate {
+        im = rotate_im(im);
     }
-    im
+
+    if file.exists() && output.overwrite {
+        std::fs::remove_file(file.clone()).unwrap();
+    }
+
+    write_rgb(im, file, output.png);
 }
 
 fn parse_color(s: &str) -> Result<(f32, f32, f32), String> {
@@ -64,14 +333,14 @@ fn parse_color(s: &str) -> Result<(f32, f32, f32), String> {
 
         let mut v = v.split(' ')
             .map(|s| {
-                let bytes = u8::from_str_radix(s, 16).unwrap();
-                bytes as f32 / 255.0
+                let bytes = u8::from_str_radix(s, 16).map_err(|_| e.clone())?;
+                Ok::<_, String>(bytes as f32 / 255.0)
             });
 
         Ok((
-            v.next().ok_or(e.clone())?,
-            v.next().ok_or(e.clone())?,
-            v.next().ok_or(e)?,
+            v.next().ok_or(e.clone())??,
+            v.next().ok_or(e.clone())??,
+            v.next().ok_or(e)??,
         ))
     } else {
         let mut v = s.split(',');
@@ -83,10 +352,95 @@ fn parse_color(s: &str) -> Result<(f32, f32, f32), String> {
     }
 }
 
+fn parse_complex(s: &str) -> Result<Complex<f64>, String> {
+    let e = format!("{} is not a valid complex number", s);
+    let mut v = s.split(',');
+    let re = v.next().ok_or(e.clone())?.trim().parse::<f64>().map_err(|_| e.clone())?;
+    let im = v.next().ok_or(e.clone())?.trim().parse::<f64>().map_err(|_| e.clone())?;
+    if v.next().is_some() {
+        return Err(e);
+    }
+    Ok(Complex::new(re, im))
+}
+
+fn parse_interval(s: &str) -> Result<Duration, String> {
+    let interval = humantime::parse_duration(s).map_err(|e| e.to_string())?;
+    if interval.is_zero() {
+        return Err(format!("{} is not a valid interval; it must be longer than 0", s));
+    }
+    Ok(interval)
+}
+
+fn parse_axes(s: &str) -> Result<(Axis, Axis), String> {
+    let e = format!("{} is not a valid pair of axes; expected two different axes of zr, zi, cr and ci", s);
+    let axis = |s: &str| match s.trim() {
+        "zr" => Ok(Axis::ZRe),
+        "zi" => Ok(Axis::ZIm),
+        "cr" => Ok(Axis::CRe),
+        "ci" => Ok(Axis::CIm),
+        _ => Err(e.clone()),
+    };
+    let (x, y) = s.split_once(',').ok_or(e.clone())?;
+    let (x, y) = (axis(x)?, axis(y)?);
+    if x == y {
+        return Err(e);
+    }
+    Ok((x, y))
+}
+
+fn parse_angles(s: &str) -> Result<[f64; 6], String> {
+    let e = format!("{} is not a valid list of 6 angles", s);
+    let angles = s.split(',').map(|v| v.trim().parse::<f64>().map_err(|_| e.clone())).collect::<Result<Vec<_>, _>>()?;
+    angles.try_into().map_err(|_| e)
+}
+
+fn parse_keyframe(s: &str) -> Result<KeyframeArg, String> {
+    let e = format!("{} is not a valid keyframe; expected FRAME followed by KEY=VALUE pairs", s);
+    let mut tokens = s.split_whitespace();
+    let frame = tokens.next().ok_or(e.clone())?.parse::<u32>().map_err(|_| e.clone())?;
+    let mut key = KeyframeArg { frame, center: None, zoom: None, angle: None, n_iterations: None, rotation_4d: None, exponent: None };
+
+    for token in tokens {
+        let (name, value) = token.split_once('=').ok_or(e.clone())?;
+        let invalid = || format!("{} is not a valid {} in keyframe {}", value, name, s);
+        match name {
+            "center" => key.center = Some(parse_complex(value)?),
+            "zoom" => key.zoom = Some(value.parse().map_err(|_| invalid())?),
+            "angle" => key.angle = Some(value.parse().map_err(|_| invalid())?),
+            "iterations" => key.n_iterations = Some(value.parse().map_err(|_| invalid())?),
+            "rotation-4d" => key.rotation_4d = Some(parse_angles(value)?),
+            "exponent" => key.exponent = Some(value.parse().map_err(|_| invalid())?),
+            _ => return Err(format!("{} is not a keyframe parameter; expected one of center, zoom, angle, iterations, rotation-4d and exponent", name)),
+        }
+    }
+    Ok(key)
+}
+
+fn parse_band(s: &str) -> Result<BandArg, String> {
+    let e = format!("{} is not a valid band; expected MIN..MAX:COLOR", s);
+    let (range, color) = s.split_once(':').ok_or(e.clone())?;
+    let (min, max) = range.split_once("..").ok_or(e.clone())?;
+
+    let min = if min.is_empty() { 0 } else { min.parse::<u32>().map_err(|_| e.clone())? };
+    let max = if max.is_empty() { None } else { Some(max.parse::<u32>().map_err(|_| e.clone())?) };
+    let color = parse_band_color(color)?;
+
+    Ok(BandArg { min, max, color })
+}
+
+fn parse_band_color(s: &str) -> Result<BandColor, String> {
+    Ok(match s {
+        "r" | "red" => BandColor::Channel(ColorChannel::Red),
+        "g" | "green" => BandColor::Channel(ColorChannel::Green),
+        "b" | "blue" => BandColor::Channel(ColorChannel::Blue),
+        _ => BandColor::Rgb(parse_color(s)?.into()),
+    })
+}
+
 fn write_rgb(im: Image<Rgb>, mut file: PathBuf, png: bool) {
     if png {
         file.set_extension("png");
-        let mut imgbuf = image::ImageBuffer::new(im.width as u32, im.width as u32);
+        let mut imgbuf = image::ImageBuffer::new(im.width as u32, im.height() as u32);
 
         for (x, y, pixel) in imgbuf.enumerate_pixels_mut() {
             let c = im.get((x as usize, y as usize)).map(|x| x * 255.0);
@@ -99,7 +453,7 @@ fn write_rgb(im: Image<Rgb>, mut file: PathBuf, png: bool) {
         file.set_extension("exr");
         exr::image::write::write_rgb_file(
             file,
-            im.width, im.width,
+            im.width, im.height(),
             |x, y| {
                 im.get((x, y)).to_tuple_rgb()
             }
@@ -157,46 +511,174 @@ enum Commands {
         n_iterations: u32,
 
         /// The number of times to sample the image. (num_samples = image_width * image_height *
-        /// samples).
+        /// samples). With --time-limit or --target-noise, this is the most samples to take.
         samples: u32,
 
-        /// The width and height of the image in pixels. Recommended to be a power of 2. 
-        image_size: u32,
+        /// The width of the image in pixels. Recommended to be a power of 2. 
+        image_width: u32,
+
+        #[command(flatten)]
+        color: ColorArgs,
 
-        /// The number of color channels to write to. 
-        #[arg(value_enum)]
-        mode: ColorChannelMode,
+        /// The height of the image in pixels. When unspecified, the image is square.
+        #[arg(long, value_name = "IMAGE_HEIGHT")]
+        image_height: Option<u32>,
 
         #[arg(short, long, value_name = "PROGRESS_UPDATE")]
         progress_update: Option<u32>,
 
-        /// The file to write the image to, excluding the extension.
-        #[arg(short, long, value_name = "FILENAME", default_value = "buddhabrot")]
-        file: PathBuf,
-        
-        /// Whether or not to overwrite the file if it already exists.
-        #[arg(short, long)]
-        overwrite: bool,
+        #[command(flatten)]
+        view: ViewArgs,
+
+        #[command(flatten)]
+        fractal: FractalArgs,
+
+        /// The seed for the random number generator. Renders with the same seed and parameters
+        /// are identical, regardless of the number of threads. When unspecified, a random seed
+        /// is picked.
+        #[arg(long, value_name = "SEED")]
+        seed: Option<u64>,
+
+        /// The file to periodically save the raw render state to, so that the render can be
+        /// continued with the resume command if it gets interrupted. Once the render finishes,
+        /// this holds its raw, unnormalized hit counts, which more samples can be added to with
+        /// --accumulate.
+        #[arg(long, value_name = "CHECKPOINT_FILE")]
+        checkpoint: Option<PathBuf>,
+
+        /// A render state file from a previous render to add `samples` more samples per pixel
+        /// to, instead of starting from a blank image. Every parameter that affects the image,
+        /// such as the iteration count and viewport, must match the previous render. The updated
+        /// state is written back to this file unless --checkpoint is given. When --seed is
+        /// unspecified, the previous render's seed is reused.
+        #[arg(long, value_name = "STATE_FILE")]
+        accumulate: Option<PathBuf>,
+
+        /// How often to save the render state to the checkpoint file, such as `30m` or `2h`.
+        #[arg(long, value_name = "CHECKPOINT_INTERVAL", value_parser = humantime::parse_duration, default_value = "10m")]
+        checkpoint_interval: Duration,
+
+        /// Stop rendering after this long, such as `30m` or `2h`, even if not every sample has
+        /// been taken. The render can be continued with the resume command if checkpointed.
+        #[arg(long, value_name = "TIME_LIMIT", value_parser = humantime::parse_duration)]
+        time_limit: Option<Duration>,
+
+        /// Stop rendering once the estimated noise drops below this fraction of the signal, such
+        /// as 0.01 for 1%, even if not every sample has been taken. The noise is estimated by
+        /// comparing two independent halves of the samples.
+        #[arg(long, value_name = "TARGET_NOISE")]
+        target_noise: Option<f64>,
+
+        /// The number of threads to render with. Defaults to the number of CPU cores.
+        #[arg(long, value_name = "THREADS")]
+        threads: Option<NonZeroUsize>,
+
+        /// How the threads count the hits of their orbits. The output is the same either way.
+        #[arg(long, value_enum, default_value_t = AccumulationArg::Local)]
+        accumulation: AccumulationArg,
+
+        #[command(flatten)]
+        preview: PreviewArgs,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    /// Render a numbered sequence of frames, interpolating the view and parameters of the render
+    /// between keyframes. Every frame is sampled with the same seed, so that the noise stays in
+    /// place from one frame to the next instead of flickering. The frames are written to
+    /// FILENAME_0000, FILENAME_0001 and so on.
+    Animate {
+        /// The number of iterations each complex number undergoes, unless set by a keyframe.
+        n_iterations: u32,
 
-        /// Whether to output the image in PNG format. If false, uses EXR. Note that this
-        /// automatically normalizes the image beforehand.
-        #[arg(long)]
-        png: bool,
+        /// The number of samples per pixel of every frame.
+        samples: u32,
 
-        /// Whether or not to normalize all pixel values between 0-1 before writing the image. 
-        #[arg(long)]
-        normalize: bool,
+        /// The width of every frame in pixels.
+        image_width: u32,
 
-        /// Whether or not to rotate the resulting image. Useful only when rendering the full
-        /// buddhabrot. 
-        #[arg(long)]
-        rotate: bool,
+        #[command(flatten)]
+        color: ColorArgs,
 
-        /// Whether or not to reflect the resulting image and add it back to the original. This
-        /// effectively doubles the number of samples but only works when rendering a symmetrical
-        /// region of the fractal. 
-        #[arg(long)]
-        reflect: bool,
+        /// The height of every frame in pixels. When unspecified, the frames are square.
+        #[arg(long, value_name = "IMAGE_HEIGHT")]
+        image_height: Option<u32>,
+
+        /// A keyframe, written as a frame number followed by the parameters to set at that frame,
+        /// such as `--keyframe "0 zoom=1" --keyframe "120 center=-0.75,0.1 zoom=50 angle=90"`.
+        /// The parameters are `center=RE,IM`, `zoom`, `angle` in degrees, `iterations`,
+        /// `rotation-4d=A,B,C,D,E,F` in degrees and the multibrot `exponent`. Parameters left out
+        /// keep the value of the previous keyframe, or of the option of the same name for the
+        /// first keyframe. Frames in between are interpolated, and the animation ends at the
+        /// last keyframe.
+        #[arg(long, value_name = "KEYFRAME", value_parser = parse_keyframe, required = true, allow_hyphen_values = true)]
+        keyframe: Vec<KeyframeArg>,
+
+        #[arg(short, long, value_name = "PROGRESS_UPDATE")]
+        progress_update: Option<u32>,
+
+        #[command(flatten)]
+        view: ViewArgs,
+
+        #[command(flatten)]
+        fractal: FractalArgs,
+
+        /// The seed for the random number generator, shared by every frame. When unspecified, a
+        /// random seed is picked.
+        #[arg(long, value_name = "SEED")]
+        seed: Option<u64>,
+
+        /// The number of threads to render with. Defaults to the number of CPU cores.
+        #[arg(long, value_name = "THREADS")]
+        threads: Option<NonZeroUsize>,
+
+        /// How the threads count the hits of their orbits. The output is the same either way.
+        #[arg(long, value_enum, default_value_t = AccumulationArg::Local)]
+        accumulation: AccumulationArg,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    /// Continue an interrupted render from its checkpoint file.
+    Resume {
+        /// The checkpoint file to resume from. The render state keeps being saved to this file.
+        state_file: PathBuf,
+
+        #[arg(short, long, value_name = "PROGRESS_UPDATE")]
+        progress_update: Option<u32>,
+
+        /// How often to save the render state to the checkpoint file, such as `30m` or `2h`.
+        #[arg(long, value_name = "CHECKPOINT_INTERVAL", value_parser = humantime::parse_duration, default_value = "10m")]
+        checkpoint_interval: Duration,
+
+        /// The number of threads to render with. Defaults to the number of CPU cores.
+        #[arg(long, value_name = "THREADS")]
+        threads: Option<NonZeroUsize>,
+
+        /// How the threads count the hits of their orbits. The output is the same either way.
+        #[arg(long, value_enum, default_value_t = AccumulationArg::Local)]
+        accumulation: AccumulationArg,
+
+        #[command(flatten)]
+        preview: PreviewArgs,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    /// Sum the raw render states of several separate renders of the same image into one.
+    Merge {
+        /// The render state files to merge. Every render must share the parameters that affect
+        /// the image, such as the iteration count and viewport, but use a different seed.
+        #[arg(required = true, num_args = 2..)]
+        state_files: Vec<PathBuf>,
+
+        /// The file to write the merged raw render state to, so that it can be merged or
+        /// accumulated onto further.
+        #[arg(long, value_name = "STATE_FILE")]
+        state_file: Option<PathBuf>,
+
+        #[command(flatten)]
+        output: OutputArgs,
     },
     Process {
         /// The full input file path to process, including the extension. 
@@ -262,6 +744,264 @@ enum Commands {
     },
 }
 
+#[derive(Args)]
+struct ColorArgs {
+    /// The number of color channels to write to. Each channel gets the orbits that escape
+    /// within a tenth of the iterations of the previous one. Cannot be used with --band.
+    #[arg(value_enum, required_unless_present = "band", conflicts_with = "band")]
+    mode: Option<ColorChannelMode>,
+
+    /// A custom color band, plotting the orbits that escape after more than MIN and at most
+    /// MAX iterations with a color. Written as `MIN..MAX:COLOR`, where MIN defaults to 0,
+    /// MAX defaults to the iteration count, and COLOR is either a channel (`r`, `g` or `b`)
+    /// or an rgb color. Can be given several times, such as `--band 5000..50000:r --band
+    /// 500..5000:g --band 50..500:b`.
+    #[arg(long, value_name = "BAND", value_parser = parse_band, allow_hyphen_values = true)]
+    band: Vec<BandArg>,
+
+    /// Which orbits to plot: the escaping ones for the Buddhabrot, the bounded ones for the
+    /// Anti-Buddhabrot, or both at once.
+    #[arg(long, value_enum, default_value_t = OrbitKindArg::Escaping)]
+    orbit_kind: OrbitKindArg,
+
+    /// The color to plot bounded orbits with when using `--orbit-kind both`, either a channel
+    /// (`r`, `g` or `b`) or an rgb color. It must not share any channel with the bands. Defaults
+    /// to the first channel that no band uses.
+    #[arg(long, value_name = "COLOR", value_parser = parse_band_color)]
+    bounded_color: Option<BandColor>,
+}
+
+impl ColorArgs {
+    /// The color bands of a render with `n` iterations.
+    fn bands(&self, n: u32) -> Vec<Band> {
+        match self.mode {
+            Some(mode) => mode.bands(n),
+            None => self.band.iter().map(|arg| Band::new(arg.min, arg.max.unwrap_or(n), arg.color)).collect(),
+        }
+    }
+
+    /// Which orbits get plotted into `bands`.
+    fn orbit_kind(&self, bands: &[Band]) -> OrbitKind {
+        match self.orbit_kind {
+            OrbitKindArg::Escaping => OrbitKind::Escaping,
+            OrbitKindArg::Bounded => OrbitKind::Bounded,
+            OrbitKindArg::Both => {
+                // When every channel is taken, the overlap gets reported once the render is set up
+                let channels = [ColorChannel::Red, ColorChannel::Green, ColorChannel::Blue].map(BandColor::Channel);
+                let free = channels.into_iter().find(|channel| bands.iter().all(|band| !band.color.overlaps(*channel)));
+                OrbitKind::Both { bounded_color: self.bounded_color.or(free).unwrap_or(BandColor::Channel(ColorChannel::Blue)) }
+            },
+        }
+    }
+}
+
+#[derive(Args)]
+struct ViewArgs {
+    /// The point of the complex plane at the center of the image, written as `re,im`.
+    #[arg(long, value_name = "CENTER", value_parser = parse_complex, default_value = "0,0", allow_hyphen_values = true)]
+    center: Complex<f64>,
+
+    /// The magnification of the image. A zoom of 1 shows the full [-2, 2] range along the
+    /// shorter side of the image.
+    #[arg(long, value_name = "ZOOM", default_value_t = 1.0)]
+    zoom: f64,
+
+    /// The counter-clockwise rotation of the image, in degrees.
+    #[arg(long, value_name = "ANGLE", default_value_t = 0.0, allow_hyphen_values = true)]
+    angle: f64,
+
+    /// The two axes of the 4D space (Re z, Im z, Re c, Im c) to project orbits onto, written
+    /// as two of `zr`, `zi`, `cr` and `ci`, such as `zr,cr`. The center and zoom apply to
+    /// this plane.
+    #[arg(long, value_name = "AXES", value_parser = parse_axes, default_value = "zr,zi")]
+    axes: (Axis, Axis),
+
+    /// Rotate the 4D space before projecting it, by six angles in degrees written as
+    /// `a,b,c,d,e,f`. These rotate the zr-zi, zr-cr, zr-ci, zi-cr, zi-ci and cr-ci planes, in
+    /// that order.
+    #[arg(long, value_name = "ANGLES", value_parser = parse_angles, allow_hyphen_values = true)]
+    rotation_4d: Option<[f64; 6]>,
+}
+
+impl ViewArgs {
+    fn viewport(&self) -> Viewport {
+        Viewport::new(self.center, self.zoom, self.angle.to_radians())
+    }
+
+    /// The angles of the 4D rotation, in radians.
+    fn rotation_4d(&self) -> [f64; 6] {
+        self.rotation_4d.unwrap_or_default().map(f64::to_radians)
+    }
+
+    /// The projection onto the chosen axes, before rotating the 4D space.
+    fn axes(&self) -> Projection {
+        Projection::axes(self.axes.0, self.axes.1)
+    }
+}
+
+#[derive(Args)]
+struct FractalArgs {
+    /// The strategy used to pick which complex numbers get sampled. The metropolis sampler
+    /// is much faster to converge when zoomed in.
+    #[arg(long, value_enum, default_value_t = SamplerKind::Uniform)]
+    sampler: SamplerKind,
+
+    /// The largest radius of a metropolis sampler's small mutations, relative to the zoom.
+    #[arg(long, value_name = "MUTATION_SIZE", default_value_t = 0.1)]
+    mutation_size: f32,
+
+    /// The probability that the metropolis sampler replaces a sample with an entirely new,
+    /// uniformly drawn sample.
+    #[arg(long, value_name = "LARGE_MUTATION_CHANCE", default_value_t = 0.2)]
+    large_mutation_chance: f32,
+
+    /// The number of mutations the metropolis sampler makes on each thread before plotting
+    /// anything.
+    #[arg(long, value_name = "BURN_IN", default_value_t = 1000)]
+    burn_in: u32,
+
+    /// The formula that orbits are iterated with.
+    #[arg(long, value_enum, default_value_t = FormulaArg::Mandelbrot)]
+    formula: FormulaArg,
+
+    /// The exponent `d` of the multibrot formula, z = z^d + c. Non-integer exponents are
+    /// allowed, but much slower.
+    #[arg(long, value_name = "EXPONENT", default_value_t = 2.0)]
+    exponent: f64,
+
+    /// How far orbits have to get from the origin to count as escaped. Larger radii give
+    /// smoother escape times, and are needed for multibrot exponents below 2.
+    #[arg(long, value_name = "ESCAPE_RADIUS", default_value_t = 2.0)]
+    escape_radius: f64,
+
+    /// The shape of the region that orbits have to leave to count as escaped.
+    #[arg(long, value_enum, default_value_t = BailoutArg::Circle)]
+    bailout: BailoutArg,
+
+    /// Render the Buddha-Julia set of this fixed `c`, written as `re,im`, by sampling the
+    /// starting points of orbits instead of `c`.
+    #[arg(long, value_name = "JULIA_C", value_parser = parse_complex, allow_hyphen_values = true)]
+    julia_c: Option<Complex<f64>>,
+
+    /// The point that orbits start from, written as `re,im`. Starting anywhere but the
+    /// origin distorts the fractal.
+    #[arg(long, value_name = "Z0", value_parser = parse_complex, default_value = "0,0", allow_hyphen_values = true)]
+    z0: Complex<f64>,
+
+    /// Whether to sample points inside the main cardioid and period-2 bulb anyway, instead of
+    /// skipping them. Their orbits never escape, so this only slows the render down.
+    #[arg(long)]
+    no_cardioid_check: bool,
+
+    /// Whether to stop iterating orbits once they fall into a cycle. This speeds up renders
+    /// with high iteration counts.
+    #[arg(long)]
+    periodicity_check: bool,
+
+    /// The floating point precision that orbits are computed with. Double precision is
+    /// slower, but needed for deep zooms and very long orbits.
+    #[arg(long, value_enum, default_value_t = PrecisionArg::F32)]
+    precision: PrecisionArg,
+}
+
+impl FractalArgs {
+    /// The formula to iterate, using `exponent` for the multibrot formula.
+    fn formula(&self, exponent: f64) -> Formula {
+        match self.formula {
+            FormulaArg::Mandelbrot => Formula::Mandelbrot,
+            FormulaArg::Multibrot => Formula::Multibrot { exponent },
+            FormulaArg::BurningShip => Formula::BurningShip,
+            FormulaArg::Tricorn => Formula::Tricorn,
+            FormulaArg::Celtic => Formula::Celtic,
+        }
+    }
+
+    fn bailout(&self) -> Bailout {
+        match self.bailout {
+            BailoutArg::Circle => Bailout::Circle,
+            BailoutArg::Square => Bailout::Square,
+            BailoutArg::Manhattan => Bailout::Manhattan,
+            BailoutArg::RealPart => Bailout::RealPart,
+            BailoutArg::ImaginaryPart => Bailout::ImaginaryPart,
+        }
+    }
+
+    fn sampler(&self) -> Sampler {
+        match self.sampler {
+            SamplerKind::Uniform => Sampler::Uniform,
+            SamplerKind::Metropolis => Sampler::Metropolis {
+                mutation_size: self.mutation_size,
+                large_mutation_chance: self.large_mutation_chance,
+                burn_in: self.burn_in,
+            },
+        }
+    }
+
+    fn precision(&self) -> Precision {
+        match self.precision {
+            PrecisionArg::F32 => Precision::F32,
+            PrecisionArg::F64 => Precision::F64,
+        }
+    }
+}
+
+#[derive(Args)]
+struct PreviewArgs {
+    /// A PNG file to periodically write a normalized, tone-mapped preview of the render to while
+    /// it is sampled.
+    #[arg(long, value_name = "PREVIEW_FILE")]
+    preview: Option<PathBuf>,
+
+    /// How often to write the preview, such as `30s` or `5m`.
+    #[arg(long, value_name = "PREVIEW_INTERVAL", value_parser = parse_interval, default_value = "1m")]
+    preview_interval: Duration,
+
+    /// Write the preview after every this many batches of samples instead of after a fixed
+    /// amount of time. A batch is at least one sample per pixel.
+    #[arg(long, value_name = "PREVIEW_BATCHES", conflicts_with = "preview_interval")]
+    preview_batches: Option<NonZeroU32>,
+
+    /// The exposure of the preview, as in the process command.
+    #[arg(long, value_name = "EXPOSURE", default_value_t = 2.5)]
+    preview_exposure: f32,
+
+    /// The gamma of the preview, as in the process command.
+    #[arg(long, value_name = "GAMMA", default_value_t = 0.45)]
+    preview_gamma: f32,
+}
+
+#[derive(Args)]
+struct OutputArgs {
+    /// The file to write the image to, excluding the extension.
+    #[arg(short, long, value_name = "FILENAME", default_value = "buddhabrot")]
+    file: PathBuf,
+    
+    /// Whether or not to overwrite the file, or the checkpoint file of a new render, if it already
+    /// exists.
+    #[arg(short, long)]
+    overwrite: bool,
+
+    /// Whether to output the image in PNG format. If false, uses EXR. Note that this
+    /// automatically normalizes the image beforehand.
+    #[arg(long)]
+    png: bool,
+
+    /// Whether or not to normalize all pixel values between 0-1 before writing the image. 
+    #[arg(long)]
+    normalize: bool,
+
+    /// Whether or not to rotate the resulting image. Useful only when rendering the full
+    /// buddhabrot. 
+    #[arg(long)]
+    rotate: bool,
+
+    /// Whether or not to reflect the resulting image and add it back to the original. This
+    /// effectively doubles the number of samples but only works when rendering a symmetrical
+    /// region of the fractal. 
+    #[arg(long)]
+    reflect: bool,
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 enum ColorChannelMode {
     /// Write to 1 color channel.
@@ -272,6 +1012,141 @@ enum ColorChannelMode {
     Rgb,
 }
 
+impl ColorChannelMode {
+    /// The color bands rendered in this mode for a render with `n` iterations. Every channel
+    /// receives the orbits that escape within a tenth of the iterations of the previous one.
+    /// Channels left without any iterations are dropped, so they stay black.
+    fn bands(self, n: u32) -> Vec<Band> {
+        let white = BandColor::Rgb(Rgb::new(1.0, 1.0, 1.0));
+        let red = Band::new(0, n, BandColor::Channel(ColorChannel::Red));
+        let green = Band::new(0, n / 10, BandColor::Channel(ColorChannel::Green));
+        let blue = Band::new(0, n / 100, BandColor::Channel(ColorChannel::Blue));
+
+        let bands = match self {
+            ColorChannelMode::R => vec![Band::new(0, n, white)],
+            ColorChannelMode::Rg => vec![red, green],
+            ColorChannelMode::Rgb => vec![red, green, blue],
+        };
+        bands.into_iter().filter(|band| band.min_iterations < band.max_iterations).collect()
+    }
+}
+
+/// A keyframe as given on the command line, before the parameters it leaves out are known.
+#[derive(Clone, Copy)]
+struct KeyframeArg {
+    frame: u32,
+    center: Option<Complex<f64>>,
+    zoom: Option<f64>,
+    angle: Option<f64>,
+    n_iterations: Option<u32>,
+    rotation_4d: Option<[f64; 6]>,
+    exponent: Option<f64>,
+}
+
+/// Fills in the parameters that each keyframe leaves out from the keyframe before it, starting
+/// from the parameters given by the options.
+fn keyframes(args: &[KeyframeArg], n_iterations: u32, view: &ViewArgs, exponent: f64) -> Vec<Keyframe> {
+    let mut args = args.to_vec();
+    args.sort_by_key(|arg| arg.frame);
+
+    let mut key = Keyframe { frame: 0, viewport: view.viewport(), rotation_4d: view.rotation_4d(), n_iterations, exponent };
+    args.iter().map(|arg| {
+        key = Keyframe {
+            frame: arg.frame,
+            viewport: Viewport::new(
+                arg.center.unwrap_or(key.viewport.center),
+                arg.zoom.unwrap_or(key.viewport.zoom),
+                arg.angle.map_or(key.viewport.angle, f64::to_radians),
+            ),
+            rotation_4d: arg.rotation_4d.map_or(key.rotation_4d, |angles| angles.map(f64::to_radians)),
+            n_iterations: arg.n_iterations.unwrap_or(key.n_iterations),
+            exponent: arg.exponent.unwrap_or(key.exponent),
+        };
+        key
+    }).collect()
+}
+
+/// A color band as given on the command line, before the iteration count is known.
+#[derive(Clone, Copy)]
+struct BandArg {
+    min: u32,
+    max: Option<u32>,
+    color: BandColor,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OrbitKindArg {
+    /// Plot the orbits that escape.
+    Escaping,
+    /// Plot the orbits that stay bounded.
+    Bounded,
+    /// Plot both kinds of orbits into separate channels.
+    Both,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum FormulaArg {
+    /// z = z^2 + c, the regular Buddhabrot.
+    Mandelbrot,
+    /// z = z^d + c, with d given by --exponent.
+    Multibrot,
+    /// z = (|Re z| + i|Im z|)^2 + c.
+    BurningShip,
+    /// z = conj(z)^2 + c, also known as the Mandelbar.
+    Tricorn,
+    /// z = |Re(z^2)| + i Im(z^2) + c.
+    Celtic,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum BailoutArg {
+    /// |z| > r.
+    Circle,
+    /// max(|Re z|, |Im z|) > r.
+    Square,
+    /// |Re z| + |Im z| > r.
+    Manhattan,
+    /// |Re z| > r.
+    RealPart,
+    /// |Im z| > r.
+    ImaginaryPart,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SamplerKind {
+    /// Sample complex numbers uniformly.
+    Uniform,
+    /// Sample complex numbers with the Metropolis-Hastings algorithm.
+    Metropolis,
+}
+
+/// How the threads of a render count hits.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum AccumulationArg {
+    /// Give every thread its own copy of the image. This is the fastest.
+    Local,
+    /// Share one image of atomic counters between all threads. This uses much less memory for
+    /// large images on many threads.
+    Atomic,
+}
+
+impl From<AccumulationArg> for Accumulation {
+    fn from(value: AccumulationArg) -> Self {
+        match value {
+            AccumulationArg::Local => Accumulation::Local,
+            AccumulationArg::Atomic => Accumulation::Atomic,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum PrecisionArg {
+    /// Compute orbits with single precision floats.
+    F32,
+    /// Compute orbits with double precision floats.
+    F64,
+}
+
 #[derive(Subcommand)]
 enum ColorizeCommand {
     /// Colorize the image with custom colors, only using values from the red color channel.
@@ -335,83 +1210,190 @@ fn main() -> clap::error::Result<(), clap::Error> {
         Commands::Generate {
             n_iterations,
             samples,
-            image_size,
-            mode,
+            image_width,
+            image_height,
+            color,
             progress_update,
-            mut file,
-            overwrite,
-            png,
-            normalize,
-            rotate,
-            reflect,
+            view,
+            fractal,
+            seed,
+            checkpoint,
+            accumulate,
+            checkpoint_interval,
+            time_limit,
+            target_noise,
+            threads,
+            accumulation,
+            preview,
+            output,
         } => {
-            let im_width = image_size as usize;
-            let im_size = im_width * im_width;
+            let im_width = image_width as usize;
+            let im_height = image_height.unwrap_or(image_width) as usize;
+            let im_size = im_width * im_height;
             let progress_update = if let Some(up) = progress_update { up as usize } else { im_size * 2 };
 
-            file.set_extension(if png { "png" } else { "exr" });
-
-            if file.exists() && !overwrite {
-                let err = Cli::command().error(ErrorKind::ValueValidation, format!("file {:?} already exists. to overwrite it, use the -o flag", file));
-                return Ok(err.print()?);
+            let file = output_file(&output)?;
+            // The state being accumulated onto is meant to be updated in place
+            if let Some(path) = checkpoint.as_deref().filter(|&path| Some(path) != accumulate.as_deref()) {
+                check_overwrite(path, &output)?;
             }
 
-            let start_time = std::time::Instant::now();
-            let mut im = match mode {
-                ColorChannelMode::R => {
-                    let im1 = Arc::new(Mutex::new(Image::<Float>::new(im_size, im_width)));
-                    sample(im1.clone(), n_iterations, samples, progress_update);
+            let previous = match &accumulate {
+                Some(path) => Some(RenderState::read(path)?),
+                None => None,
+            };
 
-                    let im = Arc::try_unwrap(im1).unwrap().into_inner().unwrap();
-                    fuse(im.clone(), im.clone(), im)
+            let seed = seed.or(previous.as_ref().map(|prev| prev.params.seed)).unwrap_or_else(rand::random);
+
+            let config = render_config(n_iterations, samples, (im_width, im_height), &color, &fractal)
+                .viewport(view.viewport())
+                .projection(view.axes().rotated(view.rotation_4d()))
+                .seed(seed)
+                .threads(threads.map_or_else(num_cpus::get, NonZeroUsize::get))
+                .accumulation(accumulation.into())
+                .time_limit(time_limit)
+                .target_noise(target_noise);
+
+            let previous_samples = previous.as_ref().map(|prev| prev.samples_done);
+            let renderer = match previous {
+                Some(prev) => config.accumulate(prev).map_err(|e| format!("cannot accumulate onto {:?}: {}", accumulate.as_ref().unwrap(), e)),
+                None => config.build().map_err(|e| e.to_string()),
+            };
+            let mut renderer = match renderer {
+                Ok(renderer) => renderer,
+                Err(e) => {
+                    let err = Cli::command().error(ErrorKind::ValueValidation, e);
+                    return Ok(err.print()?);
                 },
-                ColorChannelMode::Rg => {
-                    let im1 = Arc::new(Mutex::new(Image::<Float>::new(im_size, im_width)));
-                    sample(im1.clone(), n_iterations, samples, progress_update);
-
-                    let im2 = Arc::new(Mutex::new(Image::<Float>::new(im_size, im_width)));
-                    sample(im2.clone(), n_iterations / 10, samples, progress_update);
+            };
 
-                    let im1 = Arc::try_unwrap(im1).unwrap().into_inner().unwrap();
-                    let im2 = Arc::try_unwrap(im2).unwrap().into_inner().unwrap();
-                    fuse(im1, im2, Image::<f32>::new(im_size, im_width))
-                },
-                ColorChannelMode::Rgb => {
-                    let im1 = Arc::new(Mutex::new(Image::<Float>::new(im_size, im_width)));
-                    sample(im1.clone(), n_iterations, samples, progress_update);
+            println!("Rendering with seed {}.", seed);
+            if let Some(samples) = previous_samples {
+                println!("Adding samples to a render with {} samples.", samples);
+            }
 
-                    let im2 = Arc::new(Mutex::new(Image::<Float>::new(im_size, im_width)));
-                    sample(im2.clone(), n_iterations / 10, samples, progress_update);
+            let start_time = Instant::now();
+            render(&mut renderer, checkpoint.as_deref().or(accumulate.as_deref()), checkpoint_interval, progress_update, Previewer::new(&preview, &output))?;
+            print_summary(renderer.state(), start_time.elapsed());
 
-                    let im3 = Arc::new(Mutex::new(Image::<Float>::new(im_size, im_width)));
-                    sample(im3.clone(), n_iterations / 100, samples, progress_update);
+            write_render(renderer.state(), file, &output);
+        },
+        Commands::Animate {
+            n_iterations,
+            samples,
+            image_width,
+            color,
+            image_height,
+            keyframe,
+            progress_update,
+            view,
+            fractal,
+            seed,
+            threads,
+            accumulation,
+            output,
+        } => {
+            let im_width = image_width as usize;
+            let im_height = image_height.unwrap_or(image_width) as usize;
+            let im_size = im_width * im_height;
+            let progress_update = if let Some(up) = progress_update { up as usize } else { im_size * 2 };
 
-                    let im1 = Arc::try_unwrap(im1).unwrap().into_inner().unwrap();
-                    let im2 = Arc::try_unwrap(im2).unwrap().into_inner().unwrap();
-                    let im3 = Arc::try_unwrap(im3).unwrap().into_inner().unwrap();
-                    fuse(im1, im2, im3)
-                },
-            };
-            let elapsed = start_time.elapsed();
-            println!("Finished rendering buddhabrot in {}.", humantime::format_duration(std::time::Duration::new(elapsed.as_secs(), 0)));
-            
-            if normalize {
-                normalize_im(&mut im);
+            let animation = Animation::new(keyframes(&keyframe, n_iterations, &view, fractal.exponent));
+            let files = frame_files(&output, animation.frame_count())?;
+            let seed = seed.unwrap_or_else(rand::random);
+            let threading = threading(threads, accumulation);
+
+            let configs: Vec<_> = animation.frames().map(|key| {
+                render_config(key.n_iterations, samples, (im_width, im_height), &color, &fractal)
+                    .formula(fractal.formula(key.exponent))
+                    .viewport(key.viewport)
+                    .projection(view.axes().rotated(key.rotation_4d))
+                    .seed(seed)
+                    .threads(threading.threads)
+                    .accumulation(threading.accumulation)
+            }).collect();
+
+            // Check every frame up front, so that a bad keyframe doesn't stop the animation
+            // halfway through
+            for (frame, config) in configs.iter().enumerate() {
+                if let Err(e) = config.validate() {
+                    let err = Cli::command().error(ErrorKind::ValueValidation, format!("frame {}: {}", frame, e));
+                    return Ok(err.print()?);
+                }
             }
 
-            if reflect {
-                reflect_im(&mut im);
+            println!("Rendering {} frames with seed {}.", configs.len(), seed);
+
+            let start_time = Instant::now();
+            for ((frame, config), file) in configs.into_iter().enumerate().zip(files) {
+                println!("Rendering frame {}/{}.", frame + 1, animation.frame_count());
+                let mut renderer = config.build().expect("every frame was validated");
+                render(&mut renderer, None, Duration::MAX, progress_update, None)?;
+                write_render(renderer.state(), file, &output);
             }
 
-            if rotate {
-                rotate_im(&mut im);
+            let elapsed = start_time.elapsed();
+            println!("Finished rendering {} frames in {}.", animation.frame_count(), humantime::format_duration(Duration::new(elapsed.as_secs(), 0)));
+        },
+        Commands::Resume {
+            state_file,
+            progress_update,
+            checkpoint_interval,
+            threads,
+            accumulation,
+            preview,
+            output,
+        } => {
+            let state = RenderState::read(&state_file)?;
+            let file = output_file(&output)?;
+
+            let size = state.params.width * state.params.height;
+            let progress_update = if let Some(up) = progress_update { up as usize } else { size * 2 };
+            println!("Resuming render at {}/{} samples.", state.samples_done, state.params.total_samples());
+
+            let mut renderer = Renderer::from_state(state, threading(threads, accumulation));
+
+            let start_time = Instant::now();
+            render(&mut renderer, Some(&state_file), checkpoint_interval, progress_update, Previewer::new(&preview, &output))?;
+            print_summary(renderer.state(), start_time.elapsed());
+
+            write_render(renderer.state(), file, &output);
+        },
+        Commands::Merge {
+            state_files,
+            state_file,
+            output,
+        } => {
+            let file = output_file(&output)?;
+            let mut state = RenderState::read(&state_files[0])?;
+            let mut seeds = vec![state.params.seed];
+
+            for path in state_files.iter().skip(1) {
+                let other = RenderState::read(path)?;
+
+                if let Some(mismatch) = other.params.mismatch(&state.params) {
+                    let err = Cli::command().error(ErrorKind::Io, format!("the {} of file {:?} differs from {:?}", mismatch, path, state_files[0]));
+                    err.print()?;
+                    return Err(err);
+                }
+
+                if seeds.contains(&other.params.seed) {
+                    let err = Cli::command().error(ErrorKind::Io, format!("file {:?} was rendered with the same seed as another file, so their samples overlap", path));
+                    err.print()?;
+                    return Err(err);
+                }
+
+                seeds.push(other.params.seed);
+                state.merge(&other);
             }
 
-            if file.exists() && overwrite {
-                std::fs::remove_file(file.clone()).unwrap();
+            println!("Merged {} renders with {} samples in total.", state_files.len(), state.samples_done);
+
+            if let Some(path) = state_file {
+                state.write(&path)?;
             }
 
-            write_rgb(im, file, png);
+            write_render(&state, file, &output);
         },
         Commands::Process {
             mut input_file,
@@ -430,38 +1412,9 @@ fn main() -> clap::error::Result<(), clap::Error> {
                 normalize_im(&mut im);
             }
 
-            if let Some(exp) = exposure {
-                for px in im.pixels_mut() {
-                    px.r *= exp;
-                    px.g *= exp;
-                    px.b *= exp;
-                }
-            }
-
-            if let Some(gam) = gamma {
-                for px in im.pixels_mut() {
-                    px.r = px.r.powf(1.0 / gam);
-                    px.g = px.g.powf(1.0 / gam);
-                    px.b = px.b.powf(1.0 / gam);
-                }
-            }
+            tone_map_im(&mut im, exposure, gamma, black_point, png || clamp);
 
-            if let Some(thres) = black_point {
-                for px in im.pixels_mut() {
-                    px.r = if px.r < thres { 0.0 } else { px.r };
-                    px.g = if px.g < thres { 0.0 } else { px.g };
-                    px.b = if px.b < thres { 0.0 } else { px.b };
-                }
-            }
 
-            if png || clamp {
-                for px in im.pixels_mut() {
-                    px.r = px.r.clamp(0.0, 1.0);
-                    px.g = px.g.clamp(0.0, 1.0);
-                    px.b = px.b.clamp(0.0, 1.0);
-                }
-            }
-            
             if let Some(color) = colorize {
                 let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
 
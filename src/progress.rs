@@ -0,0 +1,139 @@
+use std::{sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, Condvar, Mutex}, time::{Duration, Instant}};
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+use crate::histogram::Histogram;
+
+
+/// Receives progress updates from a render. Updates come from every sampling thread at once.
+pub trait Progress: Send + Sync {
+    /// Called with the number of samples taken since the last update.
+    fn advance(&self, samples: u64);
+}
+
+impl Progress for ProgressBar {
+    #[inline]
+    fn advance(&self, samples: u64) {
+        self.inc(samples);
+    }
+}
+
+/// Ignores every progress update.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoProgress;
+
+impl Progress for NoProgress {
+    #[inline]
+    fn advance(&self, _samples: u64) {}
+}
+
+
+/// Creates a terminal progress bar for tracking `len` samples.
+pub fn progress_bar(len: u64) -> ProgressBar {
+    let style = ProgressStyle::with_template("{spinner:.green} [{elapsed}] [{bar:50.white/blue}] {pos}/{len} ({eta})").unwrap().progress_chars("=> ").tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏");
+    let bar = ProgressBar::new(len).with_style(style);
+    bar.inc(0);
+    bar
+}
+
+
+/// Stops a render from another thread. Clones of a token all cancel the same render.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    /// Creates a new token that hasn't been cancelled.
+    #[inline]
+    pub fn new() -> CancelToken {
+        Self::default()
+    }
+
+    /// Asks the render to stop. Every thread finishes the chunk of samples it is working on
+    /// first, so the samples taken so far can still be resumed from.
+    #[inline]
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the render has been asked to stop.
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+
+/// Takes snapshots of a render's histogram from another thread while it is being sampled.
+/// Clones of a token all take snapshots of the same render.
+#[derive(Clone, Debug, Default)]
+pub struct SnapshotToken {
+    shared: Arc<Snapshots>,
+}
+
+#[derive(Debug, Default)]
+struct Snapshots {
+    /// The number of snapshots asked for so far.
+    requested: AtomicU64,
+    /// The number of the latest snapshot taken, which answers every request up to it.
+    fulfilled: AtomicU64,
+    snapshot: Mutex<Option<Histogram>>,
+    taken: Condvar,
+}
+
+impl SnapshotToken {
+    /// Creates a new token without any snapshots asked for.
+    #[inline]
+    pub fn new() -> SnapshotToken {
+        Self::default()
+    }
+
+    /// Asks for a snapshot of the hit counts so far and waits up to `timeout` for it. Sampling
+    /// carries on while the snapshot is taken, which happens between chunks of samples. Returns
+    /// `None` if no snapshot was taken in time, in which case the request stays open and the
+    /// next call waits for the same snapshot.
+    pub fn take(&self, timeout: Duration) -> Option<Histogram> {
+        let shared = &*self.shared;
+        let fulfilled = shared.fulfilled.load(Ordering::Acquire);
+        // Only ask again once the last request has been answered, so polling doesn't pile up
+        // requests while a slow snapshot is taken
+        let _ = shared.requested.compare_exchange(fulfilled, fulfilled + 1, Ordering::AcqRel, Ordering::Acquire);
+        let request = shared.requested.load(Ordering::Acquire);
+
+        let snapshot = shared.snapshot.lock().unwrap();
+        let (mut snapshot, _) = shared.taken.wait_timeout_while(snapshot, timeout, |_| shared.fulfilled.load(Ordering::Acquire) < request).unwrap();
+        snapshot.take()
+    }
+
+    /// The latest request that hasn't been answered yet, if any.
+    #[inline]
+    pub(crate) fn pending(&self) -> Option<u64> {
+        let requested = self.shared.requested.load(Ordering::Acquire);
+        (requested > self.shared.fulfilled.load(Ordering::Acquire)).then_some(requested)
+    }
+
+    /// Answers every request up to `request` with `snapshot`, unless they were answered already.
+    pub(crate) fn fulfil(&self, request: u64, snapshot: Histogram) {
+        let mut current = self.shared.snapshot.lock().unwrap();
+        if self.shared.fulfilled.fetch_max(request, Ordering::AcqRel) < request {
+            *current = Some(snapshot);
+            self.shared.taken.notify_all();
+        }
+    }
+}
+
+
+/// Lets a call to [`sample`](crate::sample::sample) report its progress, be stopped early and
+/// have snapshots taken of it.
+#[derive(Clone, Copy)]
+pub struct Hooks<'a> {
+    pub progress: &'a dyn Progress,
+    /// The number of samples each thread takes between progress updates.
+    pub progress_update: usize,
+    pub cancel: &'a CancelToken,
+    /// Asks for snapshots of the histogram while sampling.
+    pub snapshots: &'a SnapshotToken,
+    /// When to stop sampling, as if cancelled.
+    pub deadline: Option<Instant>,
+}
@@ -0,0 +1,440 @@
+use std::{fmt, time::{Duration, Instant}};
+
+use crate::{color::{ColorChannel, Rgb}, complex::Complex, formula::{Bailout, Formula}, images::Image, progress::{CancelToken, Hooks, NoProgress, Progress, SnapshotToken}, sample::{sample, Accumulation, Band, BandColor, OrbitKind, Precision, Projection, Sampler, Threading, Viewport, CHUNK_SIZE}, state::{RenderParams, RenderState}};
+
+
+/// A problem with the settings of a render.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigError {
+    /// The image has no pixels.
+    EmptyImage,
+    /// The zoom isn't a positive number.
+    Zoom(f64),
+    /// The exponent of a Multibrot formula isn't a finite number greater than 1.
+    Exponent(f64),
+    /// The escape radius isn't a positive number.
+    EscapeRadius(f64),
+    /// The directions of the projection are parallel.
+    Projection,
+    /// A band's range of escape times is empty or exceeds the iteration count.
+    Band { min_iterations: u32, max_iterations: u32, n_iterations: u32 },
+    /// Bounded orbits are plotted separately with a color that shares a channel with a band.
+    BoundedColor { min_iterations: u32, max_iterations: u32 },
+    /// The target noise isn't a positive number.
+    TargetNoise(f64),
+    /// A parameter differs from the render being accumulated onto.
+    Mismatch(&'static str),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::EmptyImage => write!(f, "the image must be at least 1x1 pixels"),
+            ConfigError::Zoom(zoom) => write!(f, "zoom must be greater than 0, got {}", zoom),
+            ConfigError::Exponent(exponent) => write!(f, "exponent must be greater than 1, got {}", exponent),
+            ConfigError::EscapeRadius(radius) => write!(f, "escape radius must be greater than 0, got {}", radius),
+            ConfigError::Projection => write!(f, "the projection must not squash the image into a line"),
+            ConfigError::Band { min_iterations, max_iterations, n_iterations } => {
+                write!(f, "band {}..{} must be a non-empty range within the {} iterations", min_iterations, max_iterations, n_iterations)
+            },
+            ConfigError::BoundedColor { min_iterations, max_iterations } => {
+                write!(f, "the color of bounded orbits must not share a channel with band {}..{}", min_iterations, max_iterations)
+            },
+            ConfigError::TargetNoise(noise) => write!(f, "target noise must be greater than 0, got {}", noise),
+            ConfigError::Mismatch(mismatch) => write!(f, "the {} differs from the previous render", mismatch),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+
+/// The settings of a render, built up by chaining setters onto [`RenderConfig::new`].
+#[derive(Clone, Debug)]
+pub struct RenderConfig {
+    params: RenderParams,
+    threading: Threading,
+    time_limit: Option<Duration>,
+    target_noise: Option<f64>,
+}
+
+impl RenderConfig {
+    /// Starts configuring a render of `width` by `height` pixels, taking `samples` samples per
+    /// pixel and iterating each for at most `n_iterations` iterations.
+    ///
+    /// By default, orbits of the Mandelbrot formula that leave the circle of radius 2 are
+    /// plotted in white for every escape time over the full fractal with the uniform sampler and
+    /// a random seed, skipping the main cardioid, in single precision on every CPU core.
+    pub fn new(n_iterations: u32, samples: u32, width: usize, height: usize) -> RenderConfig {
+        let white = BandColor::Rgb(Rgb::new(1.0, 1.0, 1.0));
+        Self {
+            params: RenderParams {
+                n_iterations,
+                formula: Formula::default(),
+                escape_radius: 2.0,
+                bailout: Bailout::default(),
+                julia: None,
+                z0: Complex::new(0.0, 0.0),
+                samples,
+                width,
+                height,
+                bands: vec![Band::new(0, n_iterations, white)],
+                orbit_kind: OrbitKind::default(),
+                viewport: Viewport::default(),
+                projection: Projection::default(),
+                sampler: Sampler::default(),
+                seed: rand::random(),
+                cardioid_check: true,
+                periodicity_check: false,
+                precision: Precision::default(),
+            },
+            threading: Threading::default(),
+            time_limit: None,
+            target_noise: None,
+        }
+    }
+
+    /// Sets the formula that orbits are iterated with.
+    pub fn formula(mut self, formula: Formula) -> Self {
+        self.params.formula = formula;
+        self
+    }
+
+    /// Sets how far orbits have to get from the origin to count as escaped, and the shape of
+    /// the region they have to leave.
+    pub fn bailout(mut self, escape_radius: f64, bailout: Bailout) -> Self {
+        self.params.escape_radius = escape_radius;
+        self.params.bailout = bailout;
+        self
+    }
+
+    /// Renders the Buddha-Julia set of `julia` instead of the regular Buddhabrot: every orbit is
+    /// iterated with this fixed `c`, and the starting points of the orbits get sampled.
+    pub fn julia(mut self, julia: Option<Complex<f64>>) -> Self {
+        self.params.julia = julia;
+        self
+    }
+
+    /// Sets the point that orbits start from before their first iteration.
+    pub fn z0(mut self, z0: Complex<f64>) -> Self {
+        self.params.z0 = z0;
+        self
+    }
+
+    /// Sets the escape time bands that orbits are plotted into.
+    pub fn bands(mut self, bands: Vec<Band>) -> Self {
+        self.params.bands = bands;
+        self
+    }
+
+    /// Plots each of the escape time bands into a single color channel.
+    pub fn channel_bands(self, bands: &[(u32, u32, ColorChannel)]) -> Self {
+        let bands = bands.iter().map(|&(min, max, channel)| Band::new(min, max, BandColor::Channel(channel))).collect();
+        self.bands(bands)
+    }
+
+    /// Sets which orbits get plotted.
+    pub fn orbit_kind(mut self, orbit_kind: OrbitKind) -> Self {
+        self.params.orbit_kind = orbit_kind;
+        self
+    }
+
+    /// Sets the region of the complex plane that gets rendered.
+    pub fn viewport(mut self, viewport: Viewport) -> Self {
+        self.params.viewport = viewport;
+        self
+    }
+
+    /// Sets how orbits are projected from the 4D space `(Re z, Im z, Re c, Im c)` onto the
+    /// plane that the viewport looks at.
+    pub fn projection(mut self, projection: Projection) -> Self {
+        self.params.projection = projection;
+        self
+    }
+
+    /// Sets the strategy used to pick which complex numbers get sampled.
+    pub fn sampler(mut self, sampler: Sampler) -> Self {
+        self.params.sampler = sampler;
+        self
+    }
+
+    /// Sets the seed of the random number generator.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.params.seed = seed;
+        self
+    }
+
+    /// Sets whether to skip points inside the main cardioid and period-2 bulb.
+    pub fn cardioid_check(mut self, cardioid_check: bool) -> Self {
+        self.params.cardioid_check = cardioid_check;
+        self
+    }
+
+    /// Sets whether to stop iterating orbits once they fall into a cycle.
+    pub fn periodicity_check(mut self, periodicity_check: bool) -> Self {
+        self.params.periodicity_check = periodicity_check;
+        self
+    }
+
+    /// Sets the floating point precision that orbits are computed with.
+    pub fn precision(mut self, precision: Precision) -> Self {
+        self.params.precision = precision;
+        self
+    }
+
+    /// Sets the number of threads to render with.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threading.threads = threads.max(1);
+        self
+    }
+
+    /// Sets how the threads count the hits of their orbits.
+    pub fn accumulation(mut self, accumulation: Accumulation) -> Self {
+        self.threading.accumulation = accumulation;
+        self
+    }
+
+    /// Stops the render once it has run for `time_limit`, even if it hasn't taken every sample.
+    pub fn time_limit(mut self, time_limit: Option<Duration>) -> Self {
+        self.time_limit = time_limit;
+        self
+    }
+
+    /// Stops the render once its estimated relative noise drops to `target_noise`, even if it
+    /// hasn't taken every sample. See [`RenderState::noise`].
+    pub fn target_noise(mut self, target_noise: Option<f64>) -> Self {
+        self.target_noise = target_noise;
+        self
+    }
+
+    /// The parameters of the render as configured so far.
+    #[inline]
+    pub fn params(&self) -> &RenderParams {
+        &self.params
+    }
+
+    /// Checks the configuration and creates a renderer for a new, blank render.
+    pub fn build(self) -> Result<Renderer, ConfigError> {
+        self.validate()?;
+        let state = RenderState::new(self.params.clone());
+        Ok(self.renderer(state))
+    }
+
+    /// Checks the configuration and creates a renderer that adds its samples on top of a previous
+    /// render, which must share every parameter that affects the image.
+    pub fn accumulate(self, mut previous: RenderState) -> Result<Renderer, ConfigError> {
+        self.validate()?;
+        if let Some(mismatch) = previous.params.mismatch(&self.params) {
+            return Err(ConfigError::Mismatch(mismatch));
+        }
+
+        // Keep counting samples from where the previous render stopped, so that a reused seed
+        // never repeats samples
+        let samples = previous.params.samples + self.params.samples;
+        previous.params = RenderParams { samples, ..self.params.clone() };
+        Ok(self.renderer(previous))
+    }
+
+    fn renderer(self, state: RenderState) -> Renderer {
+        let mut renderer = Renderer::from_state(state, self.threading);
+        renderer.set_time_limit(self.time_limit);
+        renderer.set_target_noise(self.target_noise);
+        renderer
+    }
+
+    /// Checks the configuration without creating a renderer, finding the same problems as
+    /// [`RenderConfig::build`].
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let p = &self.params;
+        if p.width == 0 || p.height == 0 {
+            return Err(ConfigError::EmptyImage);
+        }
+        if p.viewport.zoom <= 0.0 || !p.viewport.zoom.is_finite() {
+            return Err(ConfigError::Zoom(p.viewport.zoom));
+        }
+        if let Formula::Multibrot { exponent } = p.formula {
+            if exponent <= 1.0 || !exponent.is_finite() {
+                return Err(ConfigError::Exponent(exponent));
+            }
+        }
+        if p.projection.is_degenerate() {
+            return Err(ConfigError::Projection);
+        }
+        if p.escape_radius <= 0.0 || !p.escape_radius.is_finite() {
+            return Err(ConfigError::EscapeRadius(p.escape_radius));
+        }
+        for band in p.bands.iter() {
+            if band.max_iterations > p.n_iterations || band.min_iterations >= band.max_iterations {
+                return Err(ConfigError::Band { min_iterations: band.min_iterations, max_iterations: band.max_iterations, n_iterations: p.n_iterations });
+            }
+        }
+        if let OrbitKind::Both { bounded_color } = p.orbit_kind {
+            if let Some(band) = p.bands.iter().find(|band| band.color.overlaps(bounded_color)) {
+                return Err(ConfigError::BoundedColor { min_iterations: band.min_iterations, max_iterations: band.max_iterations });
+            }
+        }
+        if let Some(noise) = self.target_noise.filter(|&noise| noise <= 0.0 || noise.is_nan()) {
+            return Err(ConfigError::TargetNoise(noise));
+        }
+        Ok(())
+    }
+}
+
+
+/// Runs a render, sampling it batch by batch.
+pub struct Renderer {
+    state: RenderState,
+    threading: Threading,
+    progress: Box<dyn Progress>,
+    /// The number of samples each thread takes between progress updates.
+    progress_update: usize,
+    cancel: CancelToken,
+    snapshots: SnapshotToken,
+    time_limit: Option<Duration>,
+    /// When the time limit runs out, counting from the first batch.
+    deadline: Option<Instant>,
+    target_noise: Option<f64>,
+    /// The noise estimated after the last batch, if there is a target noise.
+    noise: Option<f64>,
+}
+
+impl Renderer {
+    /// Creates a renderer that continues the render in `state`.
+    pub fn from_state(state: RenderState, threading: Threading) -> Renderer {
+        Self {
+            state,
+            threading,
+            progress: Box::new(NoProgress),
+            progress_update: CHUNK_SIZE,
+            cancel: CancelToken::new(),
+            snapshots: SnapshotToken::new(),
+            time_limit: None,
+            deadline: None,
+            target_noise: None,
+            noise: None,
+        }
+    }
+
+    /// Stops the render once it has run for `time_limit`, counting from its first batch.
+    pub fn set_time_limit(&mut self, time_limit: Option<Duration>) {
+        self.time_limit = time_limit;
+        self.deadline = None;
+    }
+
+    /// Stops the render once its estimated relative noise drops to `target_noise`. The noise is
+    /// estimated after every batch.
+    pub fn set_target_noise(&mut self, target_noise: Option<f64>) {
+        self.target_noise = target_noise;
+    }
+
+    /// Reports the progress of the render to `progress`, updating it every `progress_update`
+    /// samples on each thread.
+    pub fn set_progress<P: Progress + 'static>(&mut self, progress: P, progress_update: usize) {
+        self.progress = Box::new(progress);
+        self.progress_update = progress_update.max(1);
+    }
+
+    /// A token that stops the render when cancelled, even from another thread. A cancelled
+    /// render keeps every sample taken so far.
+    #[inline]
+    pub fn cancel_token(&self) -> CancelToken {
+        self.cancel.clone()
+    }
+
+    /// A token that takes snapshots of the render's histogram while it is being sampled, even
+    /// from another thread. Requests are answered between chunks of samples, so sampling doesn't
+    /// stop for them, and at the start and end of every batch.
+    #[inline]
+    pub fn snapshot_token(&self) -> SnapshotToken {
+        self.snapshots.clone()
+    }
+
+    /// Whether the render has been cancelled through its [`CancelToken`].
+    #[inline]
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// The render so far.
+    #[inline]
+    pub fn state(&self) -> &RenderState {
+        &self.state
+    }
+
+    /// Stops rendering, returning the render so far.
+    #[inline]
+    pub fn into_state(self) -> RenderState {
+        self.state
+    }
+
+    /// Whether every sample of the render has been taken.
+    #[inline]
+    pub fn is_finished(&self) -> bool {
+        self.state.is_finished()
+    }
+
+    /// Whether the render should stop, because every sample has been taken, it got cancelled, it
+    /// ran out of time or it reached its target noise.
+    pub fn is_done(&self) -> bool {
+        let out_of_time = self.deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        let converged = self.target_noise.is_some_and(|target| self.noise.is_some_and(|noise| noise <= target));
+        self.is_finished() || self.is_cancelled() || out_of_time || converged
+    }
+
+    /// The number of samples in each batch. This is one sample per pixel, rounded to whole
+    /// [chunk groups](crate::sample::Sampler::chunk_group) so every batch starts on a group
+    /// boundary. Small images still get a few chunks per thread to keep every thread busy.
+    pub fn batch_size(&self) -> usize {
+        let size = self.state.params.width * self.state.params.height;
+        let group = CHUNK_SIZE * self.state.params.sampler.chunk_group();
+        size.max(self.threading.threads * group.max(CHUNK_SIZE * 4)).next_multiple_of(group)
+    }
+
+    /// Takes the next batch of samples, unless the render is [done](Renderer::is_done). Returns
+    /// the number of samples taken, which is less than a full batch if it got cancelled or ran
+    /// out of time partway through.
+    pub fn render_batch(&mut self) -> usize {
+        // Answer snapshot requests made while no batch was running
+        self.take_snapshot();
+        if let Some(time_limit) = self.time_limit {
+            self.deadline.get_or_insert_with(|| Instant::now() + time_limit);
+        }
+        let total = self.state.params.total_samples();
+        let count = self.batch_size().min(total.saturating_sub(self.state.samples_done));
+        if count == 0 || self.is_done() {
+            return 0;
+        }
+
+        let hooks = Hooks { progress: &*self.progress, progress_update: self.progress_update, cancel: &self.cancel, snapshots: &self.snapshots, deadline: self.deadline };
+        let taken = sample(&mut self.state.histogram, &self.state.params, self.state.samples_done, count, self.threading, &hooks);
+        self.state.add_samples(taken);
+        self.take_snapshot();
+
+        if self.target_noise.is_some() {
+            self.noise = self.state.noise();
+        }
+        taken
+    }
+
+    /// Takes samples until the render is [done](Renderer::is_done), returning its unnormalized
+    /// image. If the render stops early, the image holds only the samples taken until then.
+    pub fn render(&mut self) -> Image<Rgb> {
+        while !self.is_done() {
+            self.render_batch();
+        }
+        self.image()
+    }
+
+    /// Answers the latest snapshot request, if there is one.
+    fn take_snapshot(&self) {
+        if let Some(request) = self.snapshots.pending() {
+            self.snapshots.fulfil(request, self.state.histogram.clone());
+        }
+    }
+
+    /// Converts the hit counts of the render so far to an unnormalized image.
+    #[inline]
+    pub fn image(&self) -> Image<Rgb> {
+        self.state.image()
+    }
+}
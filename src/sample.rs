@@ -1,109 +1,984 @@
-use rand::{Rng, thread_rng};
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use std::{sync::{Arc, Mutex}, thread};
-
-use crate::{color::{Color, ColorChannel}, complex::Complex, images::Image};
-
-
-pub fn sample<T: Color + Clone + Copy + Send + Sync + 'static>(im: Arc<Mutex<Image<T>>>, n: u32, m: u32, progress_update: usize) {
-    let cpus = num_cpus::get();
-    let size = im.lock().unwrap().size;
-    let width = im.lock().unwrap().width;
-    let iters = size * m as usize;
-    let thread_progress_up = progress_update / cpus;
-
-    let multiprogress = MultiProgress::new();
-    let style = ProgressStyle::with_template("{spinner:.green} [{elapsed}] [{bar:50.white/blue}] {pos}/{len} ({eta})").unwrap().progress_chars("=> ").tick_chars("⠋⠙⠹⠸⠼⠴⠦⠧⠇⠏");
-    let bar = multiprogress.add(ProgressBar::new(iters as u64).with_style(style));
-    bar.inc(0);
-
-    let mut threads = Vec::new();
-
-    for id in 0..cpus {
-        // Increment the Arc's reference count to move into each thread
-        let bar = bar.clone();
-        let im = im.clone();
-
-        threads.push(thread::spawn(move || {
-            let mut rng = thread_rng();
-            let thread_progress_offset = id * thread_progress_up;
-            // Create a new thread-local image to prevent blocking
-            let mut subim = Image::<T>::new(size, width);
-
-            for i in 0..iters.div_ceil(cpus) {
-                // Generate a random complex number
-                let r1 = rng.gen_range(0f32..1f32) * 4.0 - 2.0;
-                let r2 = rng.gen_range(0f32..1f32) * 4.0 - 2.0;
-                let c = transform(Complex::new(r1, r2));
-
-                // Calculate the path of this complex number over n iterations
-                let trajectory = mandelbrot(c, n);
-
-                // Iterate through each point in the complex number's journey
-                for z in trajectory {
-                    // Convert the complex number to pixel coordinates
-                    let p = transform_inverse(z) * 0.25 + 0.5;
-                    let px = Complex::new(p.re * width as f32, p.im * (size / width) as f32).map(|x| x as i32);
-                    
-                    // Ensure the complex number is inside the image
-                    if !is_inside(width, size, px.into()) {
-                        continue;
-                    }
-
-                    // Plot the pixel
-                    subim.add(px.map(|x| x as usize).into(), T::one(ColorChannel::Red));
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{ops::Range, sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, Mutex}, thread, time::Instant};
+
+use crate::{color::{Color, ColorChannel, Rgb}, complex::{Complex, Real}, formula::{BurningShip, Celtic, EscapeTest, Formula, Iterate, Mandelbrot, Multibrot, Tricorn}, histogram::{Accumulator, AtomicHistogram, Histogram}, progress::{Hooks, SnapshotToken}, state::RenderParams};
+
+
+/// The region of the complex plane that gets mapped onto the image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Viewport {
+    /// The point of the complex plane at the center of the image.
+    pub center: Complex<f64>,
+    /// The magnification factor. A zoom of 1 shows the full [-2, 2] range along the shorter side
+    /// of the image.
+    pub zoom: f64,
+    /// The counter-clockwise rotation of the view, in radians.
+    pub angle: f64,
+}
+
+impl Viewport {
+    /// Creates a new viewport centered on `center`, magnified by `zoom` and rotated by `angle`
+    /// radians.
+    #[inline]
+    pub fn new(center: Complex<f64>, zoom: f64, angle: f64) -> Viewport {
+        Self { center, zoom, angle }
+    }
+
+    /// Maps a point in view space, where the visible region spans [-2, 2] along the shorter side
+    /// of the image, onto the complex plane.
+    #[inline]
+    pub fn transform(&self, c: Complex<f64>) -> Complex<f64> {
+        rotate(c / self.zoom, self.angle) + self.center
+    }
+
+    /// Maps a point on the complex plane into view space. This is the inverse of
+    /// [`Viewport::transform`].
+    #[inline]
+    pub fn transform_inverse(&self, c: Complex<f64>) -> Complex<f64> {
+        (c - self.center) * self.inverse_rotation()
+    }
+
+    /// The rotation and magnification that [`Viewport::transform_inverse`] applies to the offset
+    /// of a point from the center, as a single complex factor.
+    #[inline]
+    fn inverse_rotation(&self) -> Complex<f64> {
+        rotate(Complex::new(self.zoom, 0.0), -self.angle)
+    }
+}
+
+impl Default for Viewport {
+    fn default() -> Self {
+        Self::new(Complex::new(0.0, 0.0), 1.0, 0.0)
+    }
+}
+
+
+/// An axis of the 4D space `(Re z, Im z, Re c, Im c)` that the points of orbits live in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Axis {
+    ZRe,
+    ZIm,
+    CRe,
+    CIm,
+}
+
+/// Projects the points of orbits from the 4D space `(Re z, Im z, Re c, Im c)` onto the plane
+/// that the [`Viewport`] looks at. The regular Buddhabrot is the projection onto the z plane.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Projection {
+    /// The direction of the 4D space along the real axis of the viewport's plane.
+    pub x: [f64; 4],
+    /// The direction of the 4D space along the imaginary axis of the viewport's plane.
+    pub y: [f64; 4],
+}
+
+impl Projection {
+    /// The rotation planes of [`Projection::rotated`], in the order they are applied.
+    pub const PLANES: [(Axis, Axis); 6] = [
+        (Axis::ZRe, Axis::ZIm),
+        (Axis::ZRe, Axis::CRe),
+        (Axis::ZRe, Axis::CIm),
+        (Axis::ZIm, Axis::CRe),
+        (Axis::ZIm, Axis::CIm),
+        (Axis::CRe, Axis::CIm),
+    ];
+
+    /// Creates a projection onto the plane of two axes, such as `Re z` against `Re c`.
+    pub fn axes(x: Axis, y: Axis) -> Projection {
+        let unit = |axis: Axis| {
+            let mut v = [0.0; 4];
+            v[axis as usize] = 1.0;
+            v
+        };
+        Self { x: unit(x), y: unit(y) }
+    }
+
+    /// Rotates the view of the 4D space by `angles` radians in each of the
+    /// [planes](Projection::PLANES) before projecting it. Angles in the first plane rotate the
+    /// image like the viewport's angle does, while the others gradually turn one plane of the
+    /// space into another.
+    pub fn rotated(self, angles: [f64; 6]) -> Projection {
+        let (mut x, mut y) = (self.x, self.y);
+        // Rotating the view is rotating the space the other way. Projecting the rotated space
+        // onto a direction is projecting the space onto the inversely rotated direction, which
+        // undoes the rotations of the space from last to first
+        for (&(i, j), &angle) in Self::PLANES.iter().zip(angles.iter()).rev() {
+            let (sin, cos) = angle.sin_cos();
+            for v in [&mut x, &mut y] {
+                let (vi, vj) = (v[i as usize], v[j as usize]);
+                v[i as usize] = cos * vi - sin * vj;
+                v[j as usize] = cos * vj + sin * vi;
+            }
+        }
+        Self { x, y }
+    }
+
+    /// Whether this is the projection onto the z plane, which needs no work at all.
+    #[inline]
+    pub fn is_z_plane(&self) -> bool {
+        *self == Projection::default()
+    }
+
+    /// Whether the two directions of the projection are parallel, which squashes the image into
+    /// a line.
+    pub fn is_degenerate(&self) -> bool {
+        let dot = |a: &[f64; 4], b: &[f64; 4]| a.iter().zip(b).map(|(a, b)| a * b).sum::<f64>();
+        let (xx, yy, xy) = (dot(&self.x, &self.x), dot(&self.y, &self.y), dot(&self.x, &self.y));
+        xx * yy - xy * xy <= 1e-12 * xx * yy || xx * yy == 0.0
+    }
+}
+
+impl Default for Projection {
+    fn default() -> Self {
+        Self::axes(Axis::ZRe, Axis::ZIm)
+    }
+}
+
+
+/// The strategy used to pick which complex numbers get sampled.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum Sampler {
+    /// Draws every `c` uniformly from the [-2, 2] square.
+    #[default]
+    Uniform,
+    /// Explores `c` with a Metropolis-Hastings random walk, scoring each candidate by how many of
+    /// its orbit points land inside the image. This concentrates samples on the orbits that
+    /// actually contribute to the viewport, which is what makes deep zooms feasible.
+    ///
+    /// Every accepted orbit is plotted with unit weight, so the resulting image favours orbits
+    /// that spend many iterations inside the viewport.
+    Metropolis {
+        /// The largest radius of a small mutation in the complex plane, before being divided by
+        /// the viewport's zoom.
+        mutation_size: f32,
+        /// The probability of replacing `c` with an entirely new uniform sample instead of
+        /// perturbing it.
+        large_mutation_chance: f32,
+        /// The number of mutations each thread performs before it begins plotting orbits.
+        burn_in: u32,
+    },
+}
+
+impl Sampler {
+    /// The number of consecutive chunks that are always sampled together by one thread. A
+    /// Metropolis chain walks through a whole group before it restarts, since finding a starting
+    /// point that hits the image can take many samples in deep zooms.
+    #[inline]
+    pub fn chunk_group(self) -> usize {
+        match self {
+            Sampler::Uniform => 1,
+            Sampler::Metropolis { .. } => 16,
+        }
+    }
+}
+
+
+/// The floating point precision that orbits are computed with.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Precision {
+    /// Single precision. This is the fastest, but breaks down in deep zooms and very long orbits.
+    #[default]
+    F32,
+    /// Double precision.
+    F64,
+}
+
+
+/// How the threads of a render count the hits of their orbits. Both give identical results.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Accumulation {
+    /// Every thread counts hits in its own copy of the histogram, which are added together once
+    /// the threads finish. This is the fastest, but needs a full histogram per thread.
+    #[default]
+    Local,
+    /// Every thread counts hits straight into the render's histogram through atomic counters, so
+    /// no extra memory is needed however many threads there are.
+    Atomic,
+}
+
+
+/// How the work of a call to [`sample`] is spread over threads.
+#[derive(Clone, Copy, Debug)]
+pub struct Threading {
+    /// The number of threads to sample with.
+    pub threads: usize,
+    pub accumulation: Accumulation,
+}
+
+impl Default for Threading {
+    fn default() -> Self {
+        Self { threads: num_cpus::get(), accumulation: Accumulation::default() }
+    }
+}
+
+
+/// The color that a [`Band`] plots its orbits with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BandColor {
+    /// Plots orbits into a single color channel.
+    Channel(ColorChannel),
+    /// Plots orbits with an arbitrary color, spread across every channel.
+    Rgb(Rgb),
+}
+
+/// A range of escape times, whose orbits get plotted with a given color.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Band {
+    /// Orbits must take more than this many iterations to escape to be plotted in this band.
+    pub min_iterations: u32,
+    /// The most iterations an orbit can take to escape and still be plotted in this band.
+    pub max_iterations: u32,
+    pub color: BandColor,
+}
+
+impl Band {
+    /// Creates a new band plotting orbits that take more than `min_iterations` and at most
+    /// `max_iterations` iterations to escape with `color`.
+    #[inline]
+    pub fn new(min_iterations: u32, max_iterations: u32, color: BandColor) -> Band {
+        Self { min_iterations, max_iterations, color }
+    }
+
+    /// Whether an orbit that escapes after `iterations` iterations gets plotted in this band.
+    #[inline]
+    pub fn contains(&self, iterations: u32) -> bool {
+        iterations > self.min_iterations && iterations <= self.max_iterations
+    }
+}
+
+impl BandColor {
+    /// The color that each hit of an orbit adds to a pixel.
+    #[inline]
+    pub fn to_rgb(self) -> Rgb {
+        match self {
+            BandColor::Channel(channel) => Rgb::one(channel),
+            BandColor::Rgb(rgb) => rgb,
+        }
+    }
+
+    /// Whether both colors add to any of the same color channels.
+    pub fn overlaps(self, other: BandColor) -> bool {
+        let (a, b) = (self.to_rgb(), other.to_rgb());
+        (a.r != 0.0 && b.r != 0.0) || (a.g != 0.0 && b.g != 0.0) || (a.b != 0.0 && b.b != 0.0)
+    }
+}
+
+
+/// Which orbits get plotted.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OrbitKind {
+    /// Plots the orbits that escape, giving the regular Buddhabrot.
+    #[default]
+    Escaping,
+    /// Plots the orbits that never escape, giving the Anti-Buddhabrot. These have no escape time,
+    /// so they are plotted into every band regardless of its iterations.
+    Bounded,
+    /// Plots the escaping orbits into the bands and the bounded orbits with a separate color.
+    Both {
+        bounded_color: BandColor,
+    },
+}
+
+impl OrbitKind {
+    /// Whether an orbit that escaped, or stayed bounded, gets plotted.
+    #[inline]
+    fn plots(self, escaped: bool) -> bool {
+        match self {
+            OrbitKind::Escaping => escaped,
+            OrbitKind::Bounded => !escaped,
+            OrbitKind::Both { .. } => true,
+        }
+    }
+
+    /// The colors that the histogram layers of a render get plotted with: one for every band,
+    /// followed by the color of bounded orbits when they are plotted separately.
+    pub fn layer_colors(self, bands: &[Band]) -> Vec<Rgb> {
+        let mut colors: Vec<Rgb> = bands.iter().map(|band| band.color.to_rgb()).collect();
+        if let OrbitKind::Both { bounded_color } = self {
+            colors.push(bounded_color.to_rgb());
+        }
+        colors
+    }
+
+    /// Finds the histogram layers to plot an orbit into, given the number of iterations it took
+    /// to escape, or `None` if it stayed bounded. The layers are written to `layers`, which is
+    /// left empty if the orbit doesn't get plotted at all.
+    #[inline]
+    fn layers(self, bands: &[Band], escape: Option<u32>, layers: &mut Vec<usize>) {
+        layers.clear();
+        match (self, escape) {
+            (OrbitKind::Escaping, None) | (OrbitKind::Bounded, Some(_)) => {},
+            (OrbitKind::Both { .. }, None) => layers.push(bands.len()),
+            (_, escape) => {
+                let bands = bands.iter().enumerate().filter(|(_, band)| escape.is_none_or(|iterations| band.contains(iterations)));
+                layers.extend(bands.map(|(i, _)| i));
+            },
+        }
+    }
+}
+
+
+/// Maps points of orbits to pixels of an image through a [`Projection`] and a [`Viewport`]. The
+/// viewport's [inverse transform](Viewport::transform_inverse) and the scale from view space to
+/// pixels are folded into a single rotation in the precision of the orbits, so mapping a point
+/// needs no conversions.
+#[derive(Clone, Copy, Debug)]
+struct PixelMap<R> {
+    width: usize,
+    height: usize,
+    /// The directions of the projection, or `None` when projecting onto the z plane.
+    projection: Option<[[R; 4]; 2]>,
+    center: Complex<R>,
+    /// The inverse rotation of the viewport, scaled by its zoom and by the number of pixels per
+    /// unit of view space, such that the shorter side spans [-2, 2].
+    rotation: Complex<R>,
+    /// The pixel coordinates of the center of the image.
+    offset: Complex<R>,
+}
+
+impl<R: Real> PixelMap<R> {
+    #[inline]
+    fn new(viewport: Viewport, projection: Projection, width: usize, height: usize) -> PixelMap<R> {
+        // The shorter side of the image spans [-2, 2] in view space
+        let scale = width.min(height) as f64 * 0.25;
+        Self {
+            width,
+            height,
+            projection: (!projection.is_z_plane()).then(|| [projection.x.map(R::from_f64), projection.y.map(R::from_f64)]),
+            center: viewport.center.map(R::from_f64),
+            rotation: (viewport.inverse_rotation() * scale).map(R::from_f64),
+            offset: Complex::new(width as f64 * 0.5, height as f64 * 0.5).map(R::from_f64),
+        }
+    }
+
+    /// Converts the point `z` of an orbit of `c` to pixel coordinates, if it lands inside the
+    /// image.
+    #[inline]
+    fn pixel(&self, z: Complex<R>, c: Complex<R>) -> Option<(usize, usize)> {
+        let z = match &self.projection {
+            None => z,
+            Some([x, y]) => {
+                let dot = |v: &[R; 4]| v[0] * z.re + v[1] * z.im + v[2] * c.re + v[3] * c.im;
+                Complex::new(dot(x), dot(y))
+            },
+        };
+        let p = (z - self.center) * self.rotation + self.offset;
+        let px = p.map(|x| x.to_f64().floor() as i32);
+
+        if !is_inside(self.width, self.height, px.into()) {
+            return None;
+        }
+
+        Some(px.map(|x| x as usize).into())
+    }
+}
+
+
+/// Iterates the orbits of samples with the formula `F` and finds the pixels they hit.
+#[derive(Clone, Copy, Debug)]
+struct Tracer<'a, R, F> {
+    map: PixelMap<R>,
+    formula: F,
+    escape: EscapeTest<R>,
+    /// The fixed `c` of a Buddha-Julia render, whose samples are the starting points of orbits
+    /// instead.
+    julia: Option<Complex<R>>,
+    /// The point that orbits start from before their first iteration, unless they are sampled.
+    z0: Complex<R>,
+    /// The maximum number of iterations of each orbit.
+    n: u32,
+    orbit_kind: OrbitKind,
+    /// The escape time bands that orbits are plotted into.
+    bands: &'a [Band],
+    /// Whether to skip points in the formula's known interior, such as the main cardioid and
+    /// period-2 bulb, when bounded orbits are not plotted. Only set if the bailout test can't
+    /// let those points escape.
+    cardioid_check: bool,
+    /// Whether to stop iterating orbits that settle into a cycle when bounded orbits are not
+    /// plotted.
+    periodicity_check: bool,
+}
+
+impl<R: Real, F: Iterate<R>> Tracer<'_, R, F> {
+    /// Finds the first point of the orbit of the sampled point `p`, and the `c` it is iterated
+    /// with. Usually `p` is `c` itself, and the orbit starts at the first iteration of `z0`, which
+    /// is `c` again for the origin. In Buddha-Julia renders, `p` is where the orbit starts.
+    #[inline]
+    fn start(&self, p: Complex<R>) -> (Complex<R>, Complex<R>) {
+        match self.julia {
+            Some(c) => (p, c),
+            None => (self.formula.iterate(self.z0, p), p),
+        }
+    }
+
+    /// Iterates the orbit of the sampled point `p` without storing it, returning the number of
+    /// iterations it took to escape, or `None` if it stayed bounded.
+    #[inline]
+    fn escape_time(&self, p: Complex<R>) -> Option<u32> {
+        // Bounded orbits don't need to be iterated at all if they are never plotted
+        let skip_bounded = !self.orbit_kind.plots(false);
+        if skip_bounded && self.cardioid_check && self.julia.is_none() && self.formula.in_interior(p) {
+            return None;
+        }
+
+        let (z, c) = self.start(p);
+        escape_time(z, c, self.formula, self.escape, self.n, skip_bounded && self.periodicity_check)
+    }
+
+    /// Finds the histogram layers that an orbit with the escape time `escape` gets plotted into,
+    /// as in [`OrbitKind::layers`].
+    #[inline]
+    fn layers(&self, escape: Option<u32>, layers: &mut Vec<usize>) {
+        self.orbit_kind.layers(self.bands, escape, layers);
+    }
+
+    /// Replays the orbit of the sampled point `p`, given its escape time from
+    /// [`Tracer::escape_time`], and calls `f` with the pixel of every orbit point that lands
+    /// inside the image. Does nothing if the orbit has no `layers` to be plotted into.
+    #[inline]
+    fn splat<P: FnMut((usize, usize))>(&self, p: Complex<R>, escape: Option<u32>, layers: &[usize], mut f: P) {
+        if layers.is_empty() {
+            return;
+        }
+
+        let (z, c) = self.start(p);
+        for z in orbit(z, c, self.formula).take(escape.unwrap_or(self.n) as usize) {
+            if let Some(px) = self.map.pixel(z, c) {
+                f(px);
+            }
+        }
+    }
+
+    /// Traces the orbit of the sampled point `p`, collecting the layers it gets plotted into
+    /// into `layers` and the pixels it hits into `pixels`. Both are left empty if the orbit
+    /// doesn't get plotted.
+    #[inline]
+    fn trace(&self, p: Complex<R>, layers: &mut Vec<usize>, pixels: &mut Vec<(usize, usize)>) {
+        pixels.clear();
+        let escape = self.escape_time(p);
+        self.layers(escape, layers);
+        self.splat(p, escape, layers, |px| pixels.push(px));
+    }
+}
+
+
+/// The number of samples in each chunk of work. Every chunk draws from its own random number
+/// stream, so the work is split the same way no matter how many threads render it.
+pub const CHUNK_SIZE: usize = 1 << 14;
+
+/// Creates the random number generator for a chunk, seeded from both the render's seed and the
+/// chunk's index.
+#[inline]
+fn chunk_rng(seed: u64, chunk: usize) -> StdRng {
+    let mut bytes = [0; 32];
+    bytes[..8].copy_from_slice(&seed.to_le_bytes());
+    bytes[8..16].copy_from_slice(&(chunk as u64).to_le_bytes());
+    StdRng::from_seed(bytes)
+}
+
+
+/// Takes `count` samples of the render described by `params`, starting from the sample with
+/// index `start`, and adds their orbits to the layer of `hist` of every band they fall into.
+/// The work is spread over threads as described by `threading`. Each orbit is iterated once, up
+/// to the largest `max_iterations` of all bands, or for every iteration when bounded orbits are
+/// plotted.
+///
+/// Samples are grouped into chunks by their index, so a render split across several calls
+/// matches one rendered all at once. Chunks are sampled in groups of
+/// [`Sampler::chunk_group`] chunks, and starting partway through a group replays its earlier
+/// samples without plotting them, so this is cheapest when `start` is a multiple of the group
+/// size. Threads take the next unclaimed group whenever they finish one, so none of them sit idle
+/// while others still have work.
+///
+/// Progress is reported to `hooks` as samples are taken. Once it gets cancelled or its deadline
+/// passes, threads stop claiming new groups, so the samples taken always start at `start`
+/// without any gaps. Returns the number of samples taken, which is less than `count` if the call
+/// was cancelled.
+pub fn sample(hist: &mut Histogram, params: &RenderParams, start: usize, count: usize, threading: Threading, hooks: &Hooks) -> usize {
+    match params.precision {
+        Precision::F32 => sample_in::<f32>(hist, params, start, count, threading, hooks),
+        Precision::F64 => sample_in::<f64>(hist, params, start, count, threading, hooks),
+    }
+}
+
+/// Picks the implementation of the render's formula for [`sample`], computing orbits with the
+/// float type `R`.
+fn sample_in<R: Real>(hist: &mut Histogram, params: &RenderParams, start: usize, count: usize, threading: Threading, hooks: &Hooks) -> usize {
+    match params.formula {
+        Formula::Mandelbrot => sample_with::<R, _>(hist, params, Mandelbrot, start, count, threading, hooks),
+        // Squaring is the regular Mandelbrot formula, which has a known interior to skip
+        Formula::Multibrot { exponent: 2.0 } => sample_with::<R, _>(hist, params, Mandelbrot, start, count, threading, hooks),
+        Formula::Multibrot { exponent } => sample_with::<R, _>(hist, params, Multibrot::new(exponent), start, count, threading, hooks),
+        Formula::BurningShip => sample_with::<R, _>(hist, params, BurningShip, start, count, threading, hooks),
+        Formula::Tricorn => sample_with::<R, _>(hist, params, Tricorn, start, count, threading, hooks),
+        Formula::Celtic => sample_with::<R, _>(hist, params, Celtic, start, count, threading, hooks),
+    }
+}
+
+/// Works like [`sample`], but iterates orbits with a custom `formula` in the float type `R`
+/// instead of the ones picked by the `formula` and `precision` of `params`. Every other parameter
+/// is used as usual, including the ones of the bailout test.
+///
+/// The formula isn't stored along with the render, so a render state sampled this way should
+/// only be continued with the same formula.
+pub fn sample_with<R: Real, F: Iterate<R>>(hist: &mut Histogram, params: &RenderParams, formula: F, start: usize, count: usize, threading: Threading, hooks: &Hooks) -> usize {
+    let job = Job {
+        tracer: Tracer::<'_, R, F> {
+            map: PixelMap::new(params.viewport, params.projection, params.width, params.height),
+            formula,
+            escape: EscapeTest::new(params.bailout, params.escape_radius),
+            julia: params.julia.map(|c| c.map(R::from_f64)),
+            z0: params.z0.map(R::from_f64),
+            // Escaping orbits that outlast every band are never plotted, so they can be cut off
+            // early, but bounded orbits are only known to be bounded after every iteration
+            n: if params.orbit_kind.plots(false) {
+                params.n_iterations
+            } else {
+                params.bands.iter().map(|band| band.max_iterations).max().unwrap_or(0)
+            },
+            orbit_kind: params.orbit_kind,
+            bands: &params.bands,
+            // Orbits of the interior stay within a radius of 2, but may still leave small or
+            // oddly shaped bailout regions, and only orbits starting from the origin are known
+            // to stay bounded
+            cardioid_check: params.cardioid_check && params.bailout.min_escape_magnitude(params.escape_radius) >= 2.0 && params.z0 == Complex::new(0.0, 0.0),
+            periodicity_check: params.periodicity_check,
+        },
+        params,
+        start,
+        end: start + count,
+        hooks: *hooks,
+    };
+    let group = params.sampler.chunk_group();
+    let chunks = job.end.div_ceil(CHUNK_SIZE);
+    let groups = chunks.div_ceil(group);
+    let next_group = AtomicUsize::new(start / (CHUNK_SIZE * group));
+    // There is no point in spawning more threads than there are groups
+    let threads = threading.threads.min(groups - start / (CHUNK_SIZE * group)).max(1);
+    // Every thread claims groups of chunks from a shared counter until none are left, the render
+    // gets cancelled or its deadline passes. Claimed groups are always finished, so the finished
+    // chunks never have gaps.
+    let claim_chunks = || std::iter::from_fn(|| {
+        if hooks.cancel.is_cancelled() || hooks.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return None;
+        }
+        let claimed = Some(next_group.fetch_add(1, Ordering::Relaxed)).filter(|&claimed| claimed < groups)?;
+        Some(claimed * group..chunks.min((claimed + 1) * group))
+    });
+
+    match threading.accumulation {
+        Accumulation::Local => {
+            let (layers, size) = (hist.layers.len(), params.width * params.height);
+            let merged = Mutex::new(Merged { hist, request: 0, remaining: 0, active: threads });
+            thread::scope(|s| {
+                for _ in 0..threads {
+                    let (job, merged) = (&job, &merged);
+                    s.spawn(move || {
+                        // Create a new thread-local histogram to prevent blocking
+                        let mut subhist = Histogram::new(layers, size, params.width);
+                        let mut request = 0;
+                        job.run(claim_chunks(), &mut subhist, |subhist| {
+                            // A snapshot needs the hits of every thread, so each one adds its
+                            // hits to the main histogram before carrying on
+                            if hooks.snapshots.pending().is_some_and(|pending| pending > request) {
+                                merged.lock().unwrap().merge(subhist, &mut request, hooks.snapshots);
+                            }
+                        });
+
+                        // Add the thread-local histogram to the main one
+                        let mut merged = merged.lock().unwrap();
+                        merged.merge(&mut subhist, &mut request, hooks.snapshots);
+                        merged.active -= 1;
+                    });
+                }
+            });
+        },
+        Accumulation::Atomic => {
+            // Count straight into the histogram, so no second copy of it is needed
+            let shared = AtomicHistogram::new(hist);
+            let taken = AtomicU64::new(0);
+            thread::scope(|s| {
+                for _ in 0..threads {
+                    let (job, shared, taken) = (&job, &shared, &taken);
+                    s.spawn(move || job.run(claim_chunks(), &mut &*shared, |_| {
+                        // Every thread counts into the same histogram, so whichever thread sees
+                        // the request first can take the snapshot on its own
+                        if let Some(pending) = hooks.snapshots.pending() {
+                            if taken.fetch_max(pending, Ordering::Relaxed) < pending {
+                                hooks.snapshots.fulfil(pending, shared.snapshot());
+                            }
+                        }
+                    }));
+                }
+            });
+        },
+    }
+
+    let done = job.end.min(next_group.into_inner().min(groups) * group * CHUNK_SIZE);
+    done.saturating_sub(start)
+}
+
+
+/// The main histogram of a call to [`sample`] that the threads add their own hits to, along with
+/// how far they are with the snapshot being taken.
+struct Merged<'a> {
+    hist: &'a mut Histogram,
+    /// The snapshot request being answered.
+    request: u64,
+    /// The number of threads that have yet to add their hits for `request`.
+    remaining: usize,
+    /// The number of threads still sampling.
+    active: usize,
+}
+
+impl Merged<'_> {
+    /// Moves the hits of `subhist` into the main histogram. `request` is the latest snapshot
+    /// request the thread has added its hits for, and the snapshot is taken once every thread
+    /// still sampling has done so.
+    fn merge(&mut self, subhist: &mut Histogram, request: &mut u64, snapshots: &SnapshotToken) {
+        self.hist.merge(subhist);
+        subhist.clear();
+
+        if let Some(pending) = snapshots.pending().filter(|&pending| pending > self.request) {
+            self.request = pending;
+            self.remaining = self.active;
+        }
+        if *request < self.request {
+            *request = self.request;
+            self.remaining -= 1;
+            if self.remaining == 0 {
+                snapshots.fulfil(self.request, self.hist.clone());
+            }
+        }
+    }
+}
+
+
+/// The work shared by every thread of a call to [`sample`].
+struct Job<'a, R, F> {
+    tracer: Tracer<'a, R, F>,
+    params: &'a RenderParams,
+    /// The index of the first sample to plot.
+    start: usize,
+    /// The index one past the last sample to plot.
+    end: usize,
+    hooks: Hooks<'a>,
+}
+
+impl<R: Real, F: Iterate<R>> Job<'_, R, F> {
+    /// Takes the samples of every group of chunks in `groups`, adding their orbits to `acc`.
+    /// `snapshot` is called with `acc` before every chunk, to take any snapshots that were asked
+    /// for.
+    fn run<A: Accumulator>(&self, groups: impl Iterator<Item = Range<usize>>, acc: &mut A, mut snapshot: impl FnMut(&mut A)) {
+        let Self { tracer, params, start, end, hooks } = *self;
+        let (viewport, sampler) = (params.viewport, params.sampler);
+        let mut chain = Chain::new();
+        let mut orbit_layers = Vec::new();
+        let mut progress = 0;
+
+        let chunks = groups.flat_map(|group| {
+            let first = group.start;
+            group.map(move |chunk| (chunk, chunk == first))
+        });
+        for (chunk, first) in chunks {
+            snapshot(acc);
+            let mut rng = chunk_rng(params.seed, chunk);
+            let even = chunk % 2 == 0;
+            let chunk_start = chunk * CHUNK_SIZE;
+            let chunk_end = end.min(chunk_start + CHUNK_SIZE);
+
+            // Chains walk through every chunk of their group, with each chunk's own random numbers
+            if let (Sampler::Metropolis { mutation_size, large_mutation_chance, burn_in }, true) = (sampler, first) {
+                let mutation_size = R::from_f64(mutation_size as f64 / viewport.zoom);
+                chain.restart(&mut rng, &tracer, mutation_size, R::from_f64(large_mutation_chance as f64), burn_in);
+            }
+
+            for i in chunk_start..chunk_end {
+                // Samples before `start` were taken by a previous call, so they are only
+                // replayed to advance the random number generator
+                let plot = i >= start;
+
+                match sampler {
+                    Sampler::Metropolis { .. } => {
+                        // Walk the chain, plotting the orbit of whichever state it lands on
+                        chain.step(&mut rng, &tracer);
+                        if plot {
+                            for px in chain.pixels.iter() {
+                                acc.hit(*px, &chain.layers, even);
+                            }
+                        }
+                    },
+                    Sampler::Uniform => {
+                        // Find out whether the orbit of a random point escapes, then retrace its
+                        // path and plot each point that lands inside the image
+                        let p = random_point(&mut rng);
+                        let escape = tracer.escape_time(p);
+                        if plot {
+                            tracer.layers(escape, &mut orbit_layers);
+                            tracer.splat(p, escape, &orbit_layers, |px| acc.hit(px, &orbit_layers, even));
+                        }
+                    },
                 }
 
                 // Update the progress bar if needed
-                if i != 0 && (i + thread_progress_offset) % progress_update == 0 {
-                    bar.inc(progress_update as u64)
+                progress += plot as usize;
+                if progress == hooks.progress_update {
+                    hooks.progress.advance(progress as u64);
+                    progress = 0;
                 }
             }
+        }
+        hooks.progress.advance(progress as u64);
+    }
+}
+
+
+/// The state of a Metropolis-Hastings random walk over `c`. It restarts at the first chunk of
+/// every group, and its pixel buffers are reused across every chunk a thread renders.
+struct Chain<R> {
+    /// The current sampled point, which is `c` or, in Buddha-Julia renders, the start of the
+    /// orbit.
+    c: Complex<R>,
+    /// The histogram layers that the orbit of `c` gets plotted into.
+    layers: Vec<usize>,
+    /// The pixels hit by the orbit of `c`. Its length is the score of `c`, which is 0 for orbits
+    /// that don't get plotted, such as those outside of every band.
+    pixels: Vec<(usize, usize)>,
+    /// Scratch space for the layers and pixels of the orbit of a proposed mutation.
+    proposal_layers: Vec<usize>,
+    proposal: Vec<(usize, usize)>,
+    mutation_size: R,
+    large_mutation_chance: R,
+}
 
-            // Get a mutable reference to the main image, adding the thread-local iimage to it
-            let mut global_im = im.lock().unwrap();
-            for (x, y, px) in subim.into_enumerate_pixels() {
-                global_im.add((x, y), px);
+impl<R: Real> Chain<R> {
+    /// The number of uniform samples drawn while looking for a starting point that hits the
+    /// image.
+    const MAX_START_ATTEMPTS: u32 = 100_000;
+
+    /// Creates an empty chain. It must be restarted before it is stepped.
+    fn new() -> Chain<R> {
+        Self {
+            c: Complex::new(R::from_f64(0.0), R::from_f64(0.0)),
+            layers: Vec::new(),
+            pixels: Vec::new(),
+            proposal_layers: Vec::new(),
+            proposal: Vec::new(),
+            mutation_size: R::from_f64(0.0),
+            large_mutation_chance: R::from_f64(1.0),
+        }
+    }
+
+    /// Finds a new starting point for the chain and runs it for `burn_in` steps.
+    fn restart<G: Rng, F: Iterate<R>>(&mut self, rng: &mut G, tracer: &Tracer<'_, R, F>, mutation_size: R, large_mutation_chance: R, burn_in: u32) {
+        self.mutation_size = mutation_size;
+        self.large_mutation_chance = large_mutation_chance;
+        self.pixels.clear();
+
+        // Start from the best of a batch of uniform samples. If nothing hits the image, the chain
+        // keeps making large mutations until something does.
+        for _ in 0..Self::MAX_START_ATTEMPTS {
+            let c = random_point(rng);
+            tracer.trace(c, &mut self.proposal_layers, &mut self.proposal);
+            if self.proposal.len() > self.pixels.len() {
+                self.accept(c);
+            }
+            if !self.pixels.is_empty() {
+                break;
             }
-        }))
+        }
+
+        for _ in 0..burn_in {
+            self.step(rng, tracer);
+        }
     }
 
-    for thread in threads {
-        let _ = thread.join();
+    /// Proposes a mutation of `c` and accepts it with probability `min(1, score' / score)`.
+    fn step<G: Rng, F: Iterate<R>>(&mut self, rng: &mut G, tracer: &Tracer<'_, R, F>) {
+        let candidate = if self.pixels.is_empty() || R::random(rng) < self.large_mutation_chance {
+            random_point(rng)
+        } else {
+            // Perturb c by a random angle and a log-uniformly distributed radius spanning three
+            // orders of magnitude. This is symmetric, so the acceptance ratio is just the ratio
+            // of the scores.
+            let radius = self.mutation_size * (R::random(rng) * R::from_f64(-(1000f64.ln()))).exp();
+            let (sin, cos) = (R::random(rng) * R::from_f64(std::f64::consts::TAU)).sin_cos();
+            self.c + Complex::new(cos, sin) * radius
+        };
+
+        tracer.trace(candidate, &mut self.proposal_layers, &mut self.proposal);
+        let score = self.pixels.len() as f64;
+        let proposal_score = self.proposal.len() as f64;
+
+        if proposal_score > 0.0 && (proposal_score >= score || R::random(rng).to_f64() * score < proposal_score) {
+            self.accept(candidate);
+        }
     }
 
-    multiprogress.clear().unwrap();
+    /// Moves the chain to the proposed point `c`, whose orbit was traced into the proposal
+    /// buffers.
+    #[inline]
+    fn accept(&mut self, c: Complex<R>) {
+        self.c = c;
+        std::mem::swap(&mut self.layers, &mut self.proposal_layers);
+        std::mem::swap(&mut self.pixels, &mut self.proposal);
+    }
 }
 
 
+/// Generates a random point to sample, which is either `c` or the start of a Buddha-Julia orbit.
+/// This always covers the full [-2, 2] square since orbits starting outside the viewport can
+/// still pass through it.
 #[inline]
-fn transform(c: Complex<f32>) -> Complex<f32> {
-    c
+fn random_point<R: Real, G: Rng>(rng: &mut G) -> Complex<R> {
+    let r1 = R::random(rng) * R::from_f64(4.0) - R::from_f64(2.0);
+    let r2 = R::random(rng) * R::from_f64(4.0) - R::from_f64(2.0);
+    Complex::new(r1, r2)
 }
 
+
+/// Rotates a complex number counter-clockwise around the origin by `angle` radians.
 #[inline]
-fn transform_inverse(c: Complex<f32>) -> Complex<f32> {
-    c
+fn rotate(c: Complex<f64>, angle: f64) -> Complex<f64> {
+    let (sin, cos) = angle.sin_cos();
+    c * Complex::new(cos, sin)
+}
+
+#[inline]
+pub fn is_inside(width: usize, height: usize, px: (i32, i32)) -> bool {
+    (px.0 >= 0) && (px.1 >= 0) && (px.0 < width as i32) && (px.1 < height as i32)
+}
+
+/// Whether `c` lies inside the main cardioid or the period-2 bulb of the Mandelbrot set, in
+/// which case its orbit never escapes.
+#[inline]
+pub fn in_main_bulbs<R: Real>(c: Complex<R>) -> bool {
+    let x = c.re - R::from_f64(0.25);
+    let y2 = c.im * c.im;
+    let q = x * x + y2;
+    let in_cardioid = q * (q + x) <= R::from_f64(0.25) * y2;
+    let re1 = c.re + R::from_f64(1.0);
+    let in_bulb = re1 * re1 + y2 <= R::from_f64(0.0625);
+    in_cardioid || in_bulb
+}
+
+/// How close an orbit has to return to a previous point to be considered periodic, in units of
+/// the machine epsilon of the float type. For `f32` this is about `1e-6`.
+const PERIOD_EPSILON: f64 = 8.0;
+
+/// Iterates the orbit of `c` under `formula`, starting from `z`, for at most `n` iterations
+/// without storing it, returning the number of iterations it took to fail the `escape` test, or
+/// `None` if it stayed bounded. When `periodicity_check` is set, orbits that fall into a cycle are
+/// cut short, since they will never escape.
+fn escape_time<R: Real, F: Iterate<R>>(mut z: Complex<R>, c: Complex<R>, formula: F, escape: EscapeTest<R>, n: u32, periodicity_check: bool) -> Option<u32> {
+    let epsilon = R::EPSILON * R::from_f64(PERIOD_EPSILON);
+    // Brent's cycle detection: compare against a point saved at every power of two
+    let mut saved = z;
+    let mut next_save = 2;
+
+    for i in 0..n {
+        // Update z using the iteration formula, such as z = z^2 + c for the Mandelbrot set
+        z = formula.iterate(z, c);
+        // If z escapes the fractal, the orbit consists of every point before it
+        if escape.escaped(z) {
+            return Some(i + 1);
+        }
+
+        if periodicity_check {
+            if (z.re - saved.re).abs() < epsilon && (z.im - saved.im).abs() < epsilon {
+                return None;
+            }
+            if i == next_save {
+                saved = z;
+                next_save *= 2;
+            }
+        }
+    }
+    None
 }
 
+/// An endless iterator over the orbit of `c` under `formula`, starting from `z`.
 #[inline]
-pub fn is_inside(width: usize, size: usize, px: (i32, i32)) -> bool {
-    (px.0 >= 0) && (px.1 >= 0) && (px.0 < width as i32) && (px.1 < (size / width) as i32)
+fn orbit<R: Real, F: Iterate<R>>(z: Complex<R>, c: Complex<R>, formula: F) -> impl Iterator<Item = Complex<R>> {
+    std::iter::successors(Some(z), move |z| Some(formula.iterate(*z, c)))
 }
 
-fn mandelbrot(c: Complex<f32>, n: u32) -> Vec<Complex<f32>> {
-    let mut z = c;
-    let mut sequence = Vec::new();
 
-    for _ in 0..n {
-        sequence.push(z);
-        // Update z using the Mandelbrot set formula: z = z^2 + c
-        z = z * z + c;
-        // If z escapes the Mandelbrot set, return the sequence
-        if z.abs() > 2.0 { 
-            return sequence;
-         }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use crate::{progress::{CancelToken, NoProgress, SnapshotToken}, render::RenderConfig};
+
+    /// Every hit count of `hist`, including those of its even layer.
+    fn counts(hist: &Histogram) -> Vec<Vec<u64>> {
+        hist.layers.iter().chain([&hist.even]).map(|layer| layer.pixels().copied().collect()).collect()
+    }
+
+    /// Takes the first `count` samples of the render described by `params`.
+    fn render(params: &RenderParams, count: usize, threading: Threading) -> Histogram {
+        let mut hist = Histogram::new(params.bands.len(), params.width * params.height, params.width);
+        let hooks = Hooks { progress: &NoProgress, progress_update: CHUNK_SIZE, cancel: &CancelToken::new(), snapshots: &SnapshotToken::new(), deadline: None };
+        assert_eq!(sample(&mut hist, params, 0, count, threading, &hooks), count);
+        hist
+    }
+
+    #[test]
+    fn output_does_not_depend_on_threads() {
+        let uniform = RenderConfig::new(200, 1, 32, 24).seed(7).params().clone();
+        let metropolis = RenderParams {
+            sampler: Sampler::Metropolis { mutation_size: 0.1, large_mutation_chance: 0.2, burn_in: 100 },
+            ..uniform.clone()
+        };
+        for params in [&uniform, &metropolis] {
+            // Several groups and a partial one at the end, so that the threads have groups to share
+            let count = 2 * CHUNK_SIZE * params.sampler.chunk_group() + 1000;
+            let expected = counts(&render(params, count, Threading { threads: 1, accumulation: Accumulation::Local }));
+            assert!(expected[0].iter().any(|&count| count > 0));
+
+            for accumulation in [Accumulation::Local, Accumulation::Atomic] {
+                let hist = render(params, count, Threading { threads: 4, accumulation });
+                assert_eq!(counts(&hist), expected, "{:?} with {:?} accumulation", params.sampler, accumulation);
+            }
+        }
+    }
+
+    #[test]
+    fn snapshots_do_not_change_the_output() {
+        let params = RenderConfig::new(200, 1, 32, 24).seed(7).params().clone();
+        let count = 5 * CHUNK_SIZE + 1000;
+        let expected = counts(&render(&params, count, Threading { threads: 1, accumulation: Accumulation::Local }));
+
+        for accumulation in [Accumulation::Local, Accumulation::Atomic] {
+            // Ask for a snapshot before sampling starts, so it gets taken between the first chunks
+            let snapshots = SnapshotToken::new();
+            assert!(snapshots.take(Duration::ZERO).is_none());
+
+            let mut hist = Histogram::new(params.bands.len(), params.width * params.height, params.width);
+            let hooks = Hooks { progress: &NoProgress, progress_update: CHUNK_SIZE, cancel: &CancelToken::new(), snapshots: &snapshots, deadline: None };
+            assert_eq!(sample(&mut hist, &params, 0, count, Threading { threads: 4, accumulation }, &hooks), count);
+
+            assert!(snapshots.take(Duration::ZERO).is_some(), "no snapshot with {:?} accumulation", accumulation);
+            assert_eq!(counts(&hist), expected, "{:?} accumulation", accumulation);
+        }
+    }
+
+    #[test]
+    fn resuming_inside_a_group_replays_its_chain() {
+        let params = RenderParams {
+            sampler: Sampler::Metropolis { mutation_size: 0.1, large_mutation_chance: 0.2, burn_in: 100 },
+            ..RenderConfig::new(200, 1, 32, 24).seed(7).params().clone()
+        };
+        let count = CHUNK_SIZE * params.sampler.chunk_group() + 1000;
+        let threading = Threading { threads: 2, accumulation: Accumulation::Local };
+        let expected = counts(&render(&params, count, threading));
+
+        // Stop partway through the second chunk of the first group, then carry on
+        let split = CHUNK_SIZE + 500;
+        let mut hist = render(&params, split, threading);
+        let hooks = Hooks { progress: &NoProgress, progress_update: CHUNK_SIZE, cancel: &CancelToken::new(), snapshots: &SnapshotToken::new(), deadline: None };
+        assert_eq!(sample(&mut hist, &params, split, count - split, threading, &hooks), count - split);
+        assert_eq!(counts(&hist), expected);
     }
-    // If the loop completes without escaping, return an empty vector
-    Vec::new()
 }
@@ -0,0 +1,435 @@
+use std::{fs::File, io::{self, BufReader, BufWriter, Read, Write}, path::Path};
+
+use crate::{color::{ColorChannel, Rgb}, complex::Complex, formula::{Bailout, Formula}, histogram::Histogram, images::Image, sample::{Band, BandColor, OrbitKind, Precision, Projection, Sampler, Viewport, CHUNK_SIZE}};
+
+
+/// Identifies a render state file.
+const MAGIC: &[u8; 4] = b"BBST";
+/// The version of the render state format. Bump this whenever the layout changes.
+const VERSION: u32 = 12;
+
+
+/// The parameters that fully determine a render.
+#[derive(Clone, Debug)]
+pub struct RenderParams {
+    /// The number of iterations each complex number undergoes.
+    pub n_iterations: u32,
+    /// The formula that orbits are iterated with.
+    pub formula: Formula,
+    /// How far orbits have to get from the origin to count as escaped, as measured by `bailout`.
+    pub escape_radius: f64,
+    pub bailout: Bailout,
+    /// The fixed `c` of a Buddha-Julia render, which samples the starting points of orbits
+    /// instead of `c`. When `None`, the regular Buddhabrot is rendered.
+    pub julia: Option<Complex<f64>>,
+    /// The point that orbits start from before their first iteration. Ignored by Buddha-Julia
+    /// renders, which sample it instead.
+    pub z0: Complex<f64>,
+    /// The number of samples per pixel to render in total.
+    pub samples: u32,
+    pub width: usize,
+    pub height: usize,
+    /// The escape time bands that orbits are plotted into.
+    pub bands: Vec<Band>,
+    pub orbit_kind: OrbitKind,
+    pub viewport: Viewport,
+    pub projection: Projection,
+    pub sampler: Sampler,
+    pub seed: u64,
+    /// Whether to skip sampling points inside the main cardioid and period-2 bulb, whose orbits
+    /// never escape. Only used when bounded orbits are not plotted.
+    pub cardioid_check: bool,
+    /// Whether to stop iterating orbits once they settle into a cycle. Only used when bounded
+    /// orbits are not plotted.
+    pub periodicity_check: bool,
+    pub precision: Precision,
+}
+
+impl RenderParams {
+    /// The total number of samples to take over the whole render.
+    #[inline]
+    pub fn total_samples(&self) -> usize {
+        self.width * self.height * self.samples as usize
+    }
+
+    /// Finds the first parameter that prevents the hit counts of two renders from being added
+    /// together, if any.
+    pub fn mismatch(&self, other: &RenderParams) -> Option<&'static str> {
+        if self.width != other.width || self.height != other.height {
+            Some("image dimensions")
+        } else if self.bands != other.bands {
+            Some("color bands")
+        } else if self.orbit_kind != other.orbit_kind {
+            Some("orbit kind")
+        } else if self.formula != other.formula {
+            Some("formula")
+        } else if self.escape_radius != other.escape_radius || self.bailout != other.bailout {
+            Some("bailout")
+        } else if self.julia != other.julia {
+            Some("julia c")
+        } else if self.z0 != other.z0 {
+            Some("z0")
+        } else if self.n_iterations != other.n_iterations {
+            Some("iteration count")
+        } else if self.viewport != other.viewport {
+            Some("viewport")
+        } else if self.projection != other.projection {
+            Some("projection")
+        } else if std::mem::discriminant(&self.sampler) != std::mem::discriminant(&other.sampler) {
+            // The samplers weight orbits differently, so their renders estimate different images
+            Some("sampler")
+        } else {
+            None
+        }
+    }
+}
+
+
+/// A render in progress, holding the raw, unnormalized hit counts of every band.
+#[derive(Clone, Debug)]
+pub struct RenderState {
+    pub params: RenderParams,
+    /// The number of samples that have been added to `histogram` so far.
+    pub samples_done: usize,
+    /// The number of those samples that were taken in even numbered chunks, whose hits are also
+    /// counted in the `even` layer of `histogram`.
+    pub even_samples: usize,
+    pub histogram: Histogram,
+}
+
+impl RenderState {
+    /// Creates a new, blank render.
+    pub fn new(params: RenderParams) -> RenderState {
+        let layers = params.orbit_kind.layer_colors(&params.bands).len();
+        let histogram = Histogram::new(layers, params.width * params.height, params.width);
+        Self { params, samples_done: 0, even_samples: 0, histogram }
+    }
+
+    /// Counts `count` more samples as done, following on from the samples done so far.
+    pub fn add_samples(&mut self, count: usize) {
+        // The number of samples among the first `n` that fall into even numbered chunks
+        let even = |n: usize| n / (2 * CHUNK_SIZE) * CHUNK_SIZE + (n % (2 * CHUNK_SIZE)).min(CHUNK_SIZE);
+        self.even_samples += even(self.samples_done + count) - even(self.samples_done);
+        self.samples_done += count;
+    }
+
+    /// Converts the hit counts of the render to an unnormalized image.
+    pub fn image(&self) -> Image<Rgb> {
+        self.histogram.to_image(&self.params.orbit_kind.layer_colors(&self.params.bands))
+    }
+
+    /// Whether every sample of the render has been taken.
+    #[inline]
+    pub fn is_finished(&self) -> bool {
+        self.samples_done >= self.params.total_samples()
+    }
+
+    /// Estimates the relative noise of the render so far, or `None` if it has too few samples.
+    #[inline]
+    pub fn noise(&self) -> Option<f64> {
+        self.histogram.noise(self.even_samples, self.samples_done - self.even_samples)
+    }
+
+    /// Adds the hit counts and samples of another render to this one. The parameters of both
+    /// renders should have no [`RenderParams::mismatch`].
+    pub fn merge(&mut self, other: &RenderState) {
+        self.histogram.merge(&other.histogram);
+        self.samples_done += other.samples_done;
+        self.even_samples += other.even_samples;
+        self.params.samples += other.params.samples;
+    }
+
+    /// Writes the render state to `path`. The state is first written to a temporary file which
+    /// then replaces `path`, so an interrupted write never corrupts an existing state file.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let tmp = path.with_extension("tmp");
+        let mut w = BufWriter::new(File::create(&tmp)?);
+        let p = &self.params;
+
+        w.write_all(MAGIC)?;
+        write_u32(&mut w, VERSION)?;
+        write_u32(&mut w, p.n_iterations)?;
+        match p.formula {
+            Formula::Mandelbrot => w.write_all(&[0])?,
+            Formula::Multibrot { exponent } => {
+                w.write_all(&[1])?;
+                write_f64(&mut w, exponent)?;
+            },
+            Formula::BurningShip => w.write_all(&[2])?,
+            Formula::Tricorn => w.write_all(&[3])?,
+            Formula::Celtic => w.write_all(&[4])?,
+        }
+        write_f64(&mut w, p.escape_radius)?;
+        w.write_all(&[p.bailout as u8])?;
+        match p.julia {
+            None => w.write_all(&[0])?,
+            Some(c) => {
+                w.write_all(&[1])?;
+                write_f64(&mut w, c.re)?;
+                write_f64(&mut w, c.im)?;
+            },
+        }
+        write_f64(&mut w, p.z0.re)?;
+        write_f64(&mut w, p.z0.im)?;
+        write_u32(&mut w, p.samples)?;
+        write_u64(&mut w, p.width as u64)?;
+        write_u64(&mut w, p.height as u64)?;
+        write_u32(&mut w, p.bands.len() as u32)?;
+        for band in p.bands.iter() {
+            write_u32(&mut w, band.min_iterations)?;
+            write_u32(&mut w, band.max_iterations)?;
+            write_band_color(&mut w, band.color)?;
+        }
+        match p.orbit_kind {
+            OrbitKind::Escaping => w.write_all(&[0])?,
+            OrbitKind::Bounded => w.write_all(&[1])?,
+            OrbitKind::Both { bounded_color } => {
+                w.write_all(&[2])?;
+                write_band_color(&mut w, bounded_color)?;
+            },
+        }
+        write_f64(&mut w, p.viewport.center.re)?;
+        write_f64(&mut w, p.viewport.center.im)?;
+        write_f64(&mut w, p.viewport.zoom)?;
+        write_f64(&mut w, p.viewport.angle)?;
+        for v in p.projection.x.iter().chain(p.projection.y.iter()) {
+            write_f64(&mut w, *v)?;
+        }
+        match p.sampler {
+            Sampler::Uniform => {
+                w.write_all(&[0])?;
+            },
+            Sampler::Metropolis { mutation_size, large_mutation_chance, burn_in } => {
+                w.write_all(&[1])?;
+                write_f32(&mut w, mutation_size)?;
+                write_f32(&mut w, large_mutation_chance)?;
+                write_u32(&mut w, burn_in)?;
+            },
+        }
+        write_u64(&mut w, p.seed)?;
+        w.write_all(&[p.cardioid_check as u8, p.periodicity_check as u8, p.precision as u8])?;
+        write_u64(&mut w, self.samples_done as u64)?;
+        write_u64(&mut w, self.even_samples as u64)?;
+
+        for layer in self.histogram.layers.iter().chain([&self.histogram.even]) {
+            for count in layer.pixels() {
+                write_u64(&mut w, *count)?;
+            }
+        }
+
+        w.into_inner()?.sync_all()?;
+        std::fs::rename(tmp, path)
+    }
+
+    /// Reads a render state previously written with [`RenderState::write`].
+    pub fn read(path: &Path) -> io::Result<RenderState> {
+        let mut r = BufReader::new(File::open(path)?);
+
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data(format!("{:?} is not a render state file", path)));
+        }
+        let version = read_u32(&mut r)?;
+        if version != VERSION {
+            return Err(invalid_data(format!("{:?} has unsupported render state version {}", path, version)));
+        }
+
+        let n_iterations = read_u32(&mut r)?;
+        let formula = match read_u8(&mut r)? {
+            0 => Formula::Mandelbrot,
+            1 => Formula::Multibrot { exponent: read_f64(&mut r)? },
+            2 => Formula::BurningShip,
+            3 => Formula::Tricorn,
+            4 => Formula::Celtic,
+            kind => return Err(invalid_data(format!("{:?} has unknown formula {}", path, kind))),
+        };
+        let escape_radius = read_f64(&mut r)?;
+        let bailout = match read_u8(&mut r)? {
+            0 => Bailout::Circle,
+            1 => Bailout::Square,
+            2 => Bailout::Manhattan,
+            3 => Bailout::RealPart,
+            4 => Bailout::ImaginaryPart,
+            kind => return Err(invalid_data(format!("{:?} has unknown bailout {}", path, kind))),
+        };
+        let julia = match read_u8(&mut r)? {
+            0 => None,
+            1 => Some(Complex::new(read_f64(&mut r)?, read_f64(&mut r)?)),
+            kind => return Err(invalid_data(format!("{:?} has unknown julia flag {}", path, kind))),
+        };
+        let z0 = Complex::new(read_f64(&mut r)?, read_f64(&mut r)?);
+        let samples = read_u32(&mut r)?;
+        let width = read_u64(&mut r)? as usize;
+        let height = read_u64(&mut r)? as usize;
+        let mut bands = Vec::new();
+        for _ in 0..read_u32(&mut r)? {
+            let min_iterations = read_u32(&mut r)?;
+            let max_iterations = read_u32(&mut r)?;
+            let color = read_band_color(&mut r, path)?;
+            bands.push(Band::new(min_iterations, max_iterations, color));
+        }
+        let orbit_kind = match read_u8(&mut r)? {
+            0 => OrbitKind::Escaping,
+            1 => OrbitKind::Bounded,
+            2 => OrbitKind::Both { bounded_color: read_band_color(&mut r, path)? },
+            kind => return Err(invalid_data(format!("{:?} has unknown orbit kind {}", path, kind))),
+        };
+        let center = Complex::new(read_f64(&mut r)?, read_f64(&mut r)?);
+        let viewport = Viewport::new(center, read_f64(&mut r)?, read_f64(&mut r)?);
+        let mut projection = Projection::default();
+        for v in projection.x.iter_mut().chain(projection.y.iter_mut()) {
+            *v = read_f64(&mut r)?;
+        }
+        let sampler = match read_u8(&mut r)? {
+            0 => Sampler::Uniform,
+            1 => Sampler::Metropolis {
+                mutation_size: read_f32(&mut r)?,
+                large_mutation_chance: read_f32(&mut r)?,
+                burn_in: read_u32(&mut r)?,
+            },
+            kind => return Err(invalid_data(format!("{:?} has unknown sampler {}", path, kind))),
+        };
+        let seed = read_u64(&mut r)?;
+        let cardioid_check = read_u8(&mut r)? != 0;
+        let periodicity_check = read_u8(&mut r)? != 0;
+        let precision = match read_u8(&mut r)? {
+            0 => Precision::F32,
+            1 => Precision::F64,
+            kind => return Err(invalid_data(format!("{:?} has unknown precision {}", path, kind))),
+        };
+        let samples_done = read_u64(&mut r)? as usize;
+        let even_samples = read_u64(&mut r)? as usize;
+
+        let params = RenderParams { n_iterations, formula, escape_radius, bailout, julia, z0, samples, width, height, bands, orbit_kind, viewport, projection, sampler, seed, cardioid_check, periodicity_check, precision };
+        let layers = params.orbit_kind.layer_colors(&params.bands).len();
+        let mut histogram = Histogram::new(layers, width * height, width);
+        for layer in histogram.layers.iter_mut().chain([&mut histogram.even]) {
+            for count in layer.pixels_mut() {
+                *count = read_u64(&mut r)?;
+            }
+        }
+
+        Ok(Self { params, samples_done, even_samples, histogram })
+    }
+}
+
+
+#[inline]
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn write_band_color<W: Write>(w: &mut W, color: BandColor) -> io::Result<()> {
+    match color {
+        BandColor::Channel(channel) => w.write_all(&[0, channel as u8]),
+        BandColor::Rgb(rgb) => {
+            w.write_all(&[1])?;
+            write_f32(w, rgb.r)?;
+            write_f32(w, rgb.g)?;
+            write_f32(w, rgb.b)
+        },
+    }
+}
+
+fn read_band_color<R: Read>(r: &mut R, path: &Path) -> io::Result<BandColor> {
+    Ok(match read_u8(r)? {
+        0 => BandColor::Channel(match read_u8(r)? {
+            0 => ColorChannel::Red,
+            1 => ColorChannel::Green,
+            2 => ColorChannel::Blue,
+            channel => return Err(invalid_data(format!("{:?} has unknown color channel {}", path, channel))),
+        }),
+        1 => BandColor::Rgb(Rgb::new(read_f32(r)?, read_f32(r)?, read_f32(r)?)),
+        kind => return Err(invalid_data(format!("{:?} has unknown band color {}", path, kind))),
+    })
+}
+
+fn write_u32<W: Write>(w: &mut W, v: u32) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn write_u64<W: Write>(w: &mut W, v: u64) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn write_f32<W: Write>(w: &mut W, v: f32) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn write_f64<W: Write>(w: &mut W, v: f64) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn read_u8<R: Read>(r: &mut R) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_f32<R: Read>(r: &mut R) -> io::Result<f32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(f32::from_le_bytes(buf))
+}
+
+fn read_f64<R: Read>(r: &mut R) -> io::Result<f64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(f64::from_le_bytes(buf))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{render::RenderConfig, sample::Axis};
+
+    #[test]
+    fn write_read_round_trip() {
+        // Set every parameter away from its default, so that each of them gets written
+        let bands = vec![
+            Band::new(0, 50, BandColor::Channel(ColorChannel::Green)),
+            Band::new(20, 100, BandColor::Rgb(Rgb::new(0.0, 0.5, 1.0))),
+        ];
+        let mut renderer = RenderConfig::new(100, 2, 24, 16)
+            .formula(Formula::Multibrot { exponent: 2.5 })
+            .bailout(3.0, Bailout::Manhattan)
+            .julia(Some(Complex::new(-0.4, 0.6)))
+            .z0(Complex::new(0.1, -0.2))
+            .bands(bands)
+            .orbit_kind(OrbitKind::Both { bounded_color: BandColor::Channel(ColorChannel::Red) })
+            .viewport(Viewport::new(Complex::new(-0.5, 0.25), 1.5, 0.3))
+            .projection(Projection::axes(Axis::ZRe, Axis::CIm).rotated([0.1, 0.2, 0.3, 0.4, 0.5, 0.6]))
+            .sampler(Sampler::Metropolis { mutation_size: 0.05, large_mutation_chance: 0.3, burn_in: 10 })
+            .seed(42)
+            .cardioid_check(false)
+            .periodicity_check(true)
+            .precision(Precision::F64)
+            .threads(2)
+            .build()
+            .unwrap();
+        renderer.render_batch();
+        let state = renderer.into_state();
+
+        let path = std::env::temp_dir().join(format!("buddhabrot-state-test-{}.bbst", std::process::id()));
+        state.write(&path).unwrap();
+        let read = RenderState::read(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        // Floats are debug printed exactly, so this compares every parameter and hit count
+        assert_eq!(format!("{:?}", read.unwrap()), format!("{:?}", state));
+    }
+}